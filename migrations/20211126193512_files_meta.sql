@@ -0,0 +1,2 @@
+alter table files add column mime_type text null;
+alter table files add column size integer not null default 0;
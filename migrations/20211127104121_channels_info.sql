@@ -0,0 +1,3 @@
+alter table channels add column description text not null default '';
+alter table channels add column member_count integer not null default 0;
+alter table channels add column photo text null;
@@ -0,0 +1,3 @@
+alter table files add column file_name text null;
+alter table files add column duration integer null;
+alter table files add column thumbnail text null;
@@ -0,0 +1 @@
+alter table posts add column edit_date integer null;
@@ -0,0 +1 @@
+alter table posts add column deleted_at integer null;
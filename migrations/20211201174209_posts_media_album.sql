@@ -0,0 +1 @@
+alter table posts add column media_album_id integer null;
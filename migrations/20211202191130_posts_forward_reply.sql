@@ -0,0 +1,3 @@
+alter table posts add column forward_from text null;
+alter table posts add column forward_link text null;
+alter table posts add column reply_to integer null;
@@ -0,0 +1 @@
+alter table posts add column formatted_text text null;
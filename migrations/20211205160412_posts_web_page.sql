@@ -0,0 +1 @@
+alter table posts add column web_page text null;
@@ -0,0 +1,2 @@
+alter table files add column width integer null;
+alter table files add column height integer null;
@@ -0,0 +1,14 @@
+-- files of duplicated posts are moved to the earliest copy
+update post_files set post_id = (
+    select min(duplicate.id) from posts
+    inner join posts duplicate
+        on duplicate.chat_id = posts.chat_id and duplicate.telegram_id = posts.telegram_id
+    where posts.id = post_files.post_id
+)
+where post_id in (select id from posts);
+
+delete from posts where id not in (select min(id) from posts group by chat_id, telegram_id);
+
+delete from post_files where id not in (select min(id) from post_files group by post_id, file_id);
+
+create unique index posts_chat_id_telegram_id on posts (chat_id, telegram_id);
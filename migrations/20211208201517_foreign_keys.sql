@@ -0,0 +1,79 @@
+-- posts refer to channels by telegram id, so it has to be unique
+delete from channels where id not in (select max(id) from channels group by telegram_id);
+
+-- private channels have no username and a username may pass to another channel,
+-- so only usernames in use are unique
+create table channels_new (
+    id integer primary key autoincrement not null,
+    title text not null,
+    username text not null,
+    telegram_id int not null,
+    description text not null default '',
+    member_count integer not null default 0,
+    photo text null
+);
+
+insert into channels_new (id, title, username, telegram_id, description, member_count, photo)
+select id, title, username, telegram_id, description, member_count, photo
+from channels;
+
+drop table channels;
+alter table channels_new rename to channels;
+
+create unique index channels_telegram_id on channels (telegram_id);
+create unique index channels_username on channels (username) where username != '';
+
+-- posts of channels which were never saved keep placeholder channels without title and username
+insert into channels (title, username, telegram_id)
+select distinct '', '', chat_id from posts where chat_id not in (select telegram_id from channels);
+
+create table posts_new (
+    id integer primary key autoincrement not null,
+    title text,
+    link text not null,
+    telegram_id integer not null,
+    pub_date integer not null,
+    content text not null,
+    chat_id integer not null references channels (telegram_id) on delete cascade on update cascade,
+    edit_date integer null,
+    deleted_at integer null,
+    media_album_id integer null,
+    forward_from text null,
+    forward_link text null,
+    reply_to integer null,
+    formatted_text text null,
+    web_page text null
+);
+
+insert into posts_new (id, title, link, telegram_id, pub_date, content, chat_id, edit_date, deleted_at,
+    media_album_id, forward_from, forward_link, reply_to, formatted_text, web_page)
+select id, title, link, telegram_id, pub_date, content, chat_id, edit_date, deleted_at,
+    media_album_id, forward_from, forward_link, reply_to, formatted_text, web_page
+from posts;
+
+drop table posts;
+alter table posts_new rename to posts;
+
+create unique index posts_chat_id_telegram_id on posts (chat_id, telegram_id);
+-- channel posts are always selected newest first
+create index posts_chat_id_pub_date on posts (chat_id, pub_date);
+
+create index files_remote_id on files (remote_id);
+
+-- post_files.file_id used to hold files.remote_file, it refers to files.id now
+create table post_files_new (
+    id integer primary key autoincrement not null,
+    post_id int not null references posts (id) on delete cascade,
+    file_id int not null references files (id) on delete cascade,
+    unique (post_id, file_id)
+);
+
+insert into post_files_new (id, post_id, file_id)
+select min(post_files.id), post_files.post_id, files.id
+from post_files
+inner join files on files.remote_file = post_files.file_id
+inner join posts on posts.id = post_files.post_id
+group by post_files.post_id, files.id;
+
+drop table post_files;
+alter table post_files_new rename to post_files;
@@ -0,0 +1,22 @@
+-- messages a post is made of, every message of a media album refers to the album post
+create table post_messages (
+    id integer primary key autoincrement not null,
+    post_id integer not null references posts (id) on delete cascade,
+    chat_id integer not null,
+    telegram_id integer not null,
+    -- caption of the message, the post takes the first non-empty one
+    content text not null,
+    formatted_text text null,
+    deleted_at integer null
+);
+
+create unique index post_messages_chat_id_telegram_id on post_messages (chat_id, telegram_id);
+create index post_messages_post_id on post_messages (post_id);
+
+insert into post_messages (post_id, chat_id, telegram_id, content, formatted_text, deleted_at)
+select id, chat_id, telegram_id, content, formatted_text, deleted_at from posts;
+
+-- message which attached the file, so that its edit replaces only its own media
+alter table post_files add column message_id integer null;
+
+update post_files set message_id = (select telegram_id from posts where posts.id = post_files.post_id);
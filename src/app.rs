@@ -1,16 +1,24 @@
 use std::collections::HashMap;
 use crate::db::{Channel, DbService, NewChannel, Post};
-use crate::models::{File, TelegramPostId};
-use crate::telegram::{NewUpdate, TelegramService};
+use crate::feed::file_url;
+use crate::models::{post_link, File, ForwardedFrom, TelegramPostId, WebPage};
+use crate::settings::{DeletedPostsPolicy, FeedSettings, TextFormat, TitlePolicy};
+use crate::telegram::{
+    first_bold, render_formatted_text, renderer, strip_html, Entity, NewUpdate, PlainRenderer,
+    Renderer, TelegramService,
+};
 use anyhow::Context;
 use std::future::Future;
 use std::sync::Arc;
 
 const HISTORY_LIMIT: i32 = 100;
+const DELETED_POST_NOTE: &str = "This post was deleted from the channel.";
+const REPLY_SNIPPET_LENGTH: usize = 140;
 
 struct Inner {
     tg: TelegramService,
     db: DbService,
+    feed_settings: FeedSettings,
 }
 
 #[derive(Clone)]
@@ -19,12 +27,20 @@ pub struct App {
 }
 
 impl App {
-    pub fn new(tg: TelegramService, db: DbService) -> Self {
+    pub fn new(tg: TelegramService, db: DbService, feed_settings: FeedSettings) -> Self {
         Self {
-            inner: Arc::new(Inner { tg, db }),
+            inner: Arc::new(Inner {
+                tg,
+                db,
+                feed_settings,
+            }),
         }
     }
 
+    pub fn feed_settings(&self) -> &FeedSettings {
+        &self.inner.feed_settings
+    }
+
     pub async fn start(&self) -> anyhow::Result<()> {
         log::info!("starting telegram service");
         let mut updates = self.inner.tg.start().await?;
@@ -35,11 +51,58 @@ impl App {
             while let Some(update) = updates.recv().await {
                 log::info!("new update: {:?}", update);
                 match update {
-                    NewUpdate::Post(post) => {
-                        if let Err(err) = inner.db.save_channel_posts(&vec![post]).await {
+                    NewUpdate::Post(mut post) => {
+                        if let Some(forwarded_from) = post.forwarded_from.as_mut() {
+                            if let Err(err) = inner.tg.resolve_forward(forwarded_from).await {
+                                log::error!("cannot resolve forward origin: {}", err)
+                            }
+                        }
+                        match inner.db.get_channel_by_telegram_id(post.chat_id).await {
+                            Ok(Some(channel)) => {
+                                post.link = post_link(channel.username.as_str(), post.telegram_id)
+                            }
+                            // posts reference the channel, so it's saved first
+                            Ok(None) => match inner.tg.get_channel(post.chat_id).await {
+                                Ok(Some(channel)) => {
+                                    post.link =
+                                        post_link(channel.username.as_str(), post.telegram_id);
+                                    if let Err(err) = inner.db.save_channel(channel).await {
+                                        log::error!("cannot save channel: {}", err);
+                                        continue;
+                                    }
+                                }
+                                Ok(None) => {
+                                    log::info!(
+                                        "skipping post of non-channel chat {}",
+                                        post.chat_id
+                                    );
+                                    continue;
+                                }
+                                Err(err) => {
+                                    log::error!("cannot get channel {}: {}", post.chat_id, err);
+                                    continue;
+                                }
+                            },
+                            Err(err) => log::error!("cannot get channel: {}", err),
+                        }
+                        if let Err(err) = inner.db.save_channel_posts(&vec![*post]).await {
                             log::error!("cannot save channel posts: {}", err)
                         };
                     }
+                    NewUpdate::EditedPost(post) => {
+                        if let Err(err) = inner.db.update_post(&post).await {
+                            log::error!("cannot update post: {}", err)
+                        };
+                    }
+                    NewUpdate::DeletedPosts(chat_id, telegram_ids) => {
+                        if inner.feed_settings.deleted_posts == DeletedPostsPolicy::Keep {
+                            continue;
+                        }
+                        if let Err(err) = inner.db.mark_posts_deleted(chat_id, &telegram_ids).await
+                        {
+                            log::error!("cannot mark posts deleted: {}", err)
+                        };
+                    }
                     NewUpdate::Channel(channel) => {
                         if let Err(err) = inner.db.save_channel(channel).await {
                             log::error!("cannot save channel: {}", err)
@@ -99,11 +162,32 @@ impl App {
         Ok(())
     }
 
+    /// Returns known file and requests its downloading if it's not on disk yet.
+    pub async fn get_file(&self, remote_id: &str) -> anyhow::Result<Option<File>> {
+        let file = self.inner.db.get_file_by_remote_id(remote_id).await?;
+        if let Some(f) = &file {
+            if f.local_path.is_none() {
+                self.download_file(f.remote_file).await?;
+            }
+        }
+        Ok(file)
+    }
+
+    pub async fn download_file(&self, remote_file: i32) -> anyhow::Result<()> {
+        self.inner.tg.download_file(remote_file).await
+    }
+
     pub async fn get_posts_or_search(
         &self,
         channel_name: &str,
-    ) -> anyhow::Result<Option<rss::Channel>> {
-        match self.get_channel_posts(channel_name).await? {
+        text_format: Option<TextFormat>,
+        audio_only: bool,
+        before: Option<TelegramPostId>,
+    ) -> anyhow::Result<Option<(Channel, Vec<Post>)>> {
+        match self
+            .get_channel_posts(channel_name, text_format, audio_only, before)
+            .await?
+        {
             None => {
                 log::info!("posts not found, searching for new channel");
                 match self.get_new_channel(channel_name).await? {
@@ -113,7 +197,9 @@ impl App {
                     }
                     Some(_) => {
                         log::info!("found new channel, retrieving posts");
-                        Ok(self.get_channel_posts(channel_name).await?)
+                        Ok(self
+                            .get_channel_posts(channel_name, text_format, audio_only, before)
+                            .await?)
                     }
                 }
             }
@@ -121,45 +207,113 @@ impl App {
         }
     }
 
+    /// Text format requested explicitly, configured for the channel or the default one.
+    pub fn text_format(&self, channel_name: &str, requested: Option<TextFormat>) -> TextFormat {
+        let settings = &self.inner.feed_settings;
+        requested
+            .or_else(|| settings.channel_text_formats.get(channel_name).copied())
+            .unwrap_or(settings.text_format)
+    }
+
+    /// Page of channel posts, newest first, published before `before` post if it's set.
     pub async fn get_channel_posts(
         &self,
         channel_name: &str,
-    ) -> anyhow::Result<Option<rss::Channel>> {
-        let (channel, posts) = match self.inner.db.get_channel_posts(channel_name).await? {
+        text_format: Option<TextFormat>,
+        audio_only: bool,
+        before: Option<TelegramPostId>,
+    ) -> anyhow::Result<Option<(Channel, Vec<Post>)>> {
+        let settings = &self.inner.feed_settings;
+        let include_deleted = settings.deleted_posts == DeletedPostsPolicy::Mark;
+        let (channel, mut posts) = match self
+            .inner
+            .db
+            .get_channel_posts(
+                channel_name,
+                include_deleted,
+                audio_only,
+                before,
+                settings.page_size,
+            )
+            .await?
+        {
             None => return Ok(None),
-            Some(ch) => ch,
+            Some(found) => found,
         };
-        let mut items = Vec::new();
-        for p in posts.into_iter() {
-            let guid = rss::GuidBuilder::default()
-                .value(p.telegram_id().to_string())
-                .build()
-                .map_err(rss_err)?;
-            let item = rss::ItemBuilder::default()
-                .title(p.title().clone().unwrap_or_default())
-                .link(p.link().clone().to_string())
-                .guid(Some(guid))
-                .pub_date(p.pub_date().clone().to_string())
-                .content(p.content().clone().to_string())
-                .build()
-                .map_err(rss_err)?;
-            items.push(item);
+        let text_format = self.text_format(channel_name, text_format);
+        let renderer = renderer(text_format);
+        let username = Some(channel.username.as_str());
+        let reply_ids: Vec<TelegramPostId> = posts.iter().filter_map(Post::reply_to).collect();
+        let replied = match self
+            .inner
+            .db
+            .get_posts_content(channel.telegram_id, &reply_ids, include_deleted)
+            .await
+        {
+            Ok(replied) => replied,
+            Err(err) => {
+                // feed is still useful without reply snippets
+                log::error!("cannot get replied posts: {}", err);
+                HashMap::new()
+            }
+        };
+        for p in posts.iter_mut() {
+            p.summary = Some(match &p.formatted_text {
+                Some(formatted_text) => {
+                    render_formatted_text(formatted_text, &PlainRenderer, username)
+                }
+                None => strip_html(p.content()),
+            });
+            if p.title.is_none() {
+                p.title = derive_title(&self.inner.feed_settings, p);
+            }
+            match (&p.formatted_text, text_format) {
+                // rendered again to link hashtags, which need the channel
+                (Some(formatted_text), _) => {
+                    p.content = render_formatted_text(formatted_text, renderer, username)
+                }
+                (None, TextFormat::Plain) => p.content = strip_html(p.content()),
+                // markdown allows inline html
+                (None, TextFormat::Html | TextFormat::Markdown) => {}
+            }
+            if let Some(web_page) = p.web_page() {
+                let photo_url = web_page
+                    .photo
+                    .as_ref()
+                    .map(|photo| file_url(&self.inner.feed_settings, photo));
+                let card = web_page_card(renderer, web_page, photo_url);
+                p.content.push_str(card.as_str());
+            }
+
+            let mut header = String::new();
+            if p.is_deleted() {
+                let note =
+                    renderer.wrap(&Entity::Italic, renderer.text(DELETED_POST_NOTE).as_str());
+                header.push_str(renderer.paragraph(note.as_str()).as_str());
+            }
+            if let Some(forwarded_from) = p.forwarded_from() {
+                header.push_str(forward_header(renderer, forwarded_from).as_str());
+            }
+            if let Some(reply_to) = p.reply_to() {
+                let link = post_link(channel.username.as_str(), reply_to);
+                let replied_content = replied.get(&reply_to).map(String::as_str);
+                header.push_str(reply_header(renderer, link, replied_content).as_str());
+            }
+            if !header.is_empty() {
+                p.content = format!("{}{}", header, p.content);
+            }
         }
-        let feed = rss::ChannelBuilder::default()
-            .title(channel.title.clone())
-            // .description(channel.description().clone().unwrap_or_default()) TODO: description
-            // .link(channel.link().clone().to_string()) TODO: link
-            .items(items)
-            .build()
-            .map_err(|e| anyhow::anyhow!("error during building feed: {}", e))?;
-        Ok(Some(feed))
+        Ok(Some((channel, posts)))
     }
 
     async fn get_new_channel(&self, channel_name: &str) -> anyhow::Result<Option<(Channel)>> {
         match self.inner.tg.search_channel(channel_name).await? {
             None => Ok(None),
             Some(ch) => {
-                let messages = self.inner.tg.get_channel_history(ch.telegram_id, HISTORY_LIMIT).await?;
+                let mut messages = self.inner.tg.get_channel_history(ch.telegram_id, HISTORY_LIMIT).await?;
+                for msg in messages.iter_mut() {
+                    msg.link = post_link(ch.username.as_str(), msg.telegram_id);
+                }
                 self.inner.db.save_channel(ch).await?;
                 self.inner.db.save_channel_posts(&messages).await?;
 
@@ -171,28 +325,222 @@ impl App {
                     Some(ch) => ch,
                 };
                 log::info!("{:?}", saved_channel);
+                Ok(Some(saved_channel))
+            }
+        }
+    }
+}
 
-                let mut post_to_files: HashMap<TelegramPostId, Vec<i32>> = HashMap::new();
+fn forward_header(renderer: &dyn Renderer, forwarded_from: &ForwardedFrom) -> String {
+    let name = forwarded_from.name.as_deref().unwrap_or("unknown");
+    let source = match &forwarded_from.link {
+        None => renderer.text(name),
+        Some(link) => renderer.wrap(&Entity::TextUrl(link.clone()), renderer.text(name).as_str()),
+    };
+    let header = format!("{}{}", renderer.text("Forwarded from "), source);
+    renderer.paragraph(renderer.wrap(&Entity::Italic, header.as_str()).as_str())
+}
 
-                for msg in messages.iter() {
-                    let files = post_to_files.entry(msg.telegram_id).or_default();
-                    files.extend(msg.files.clone())
-                }
+fn reply_header(renderer: &dyn Renderer, link: String, replied_content: Option<&str>) -> String {
+    let reply_link = renderer.wrap(
+        &Entity::TextUrl(link),
+        renderer.text("In reply to").as_str(),
+    );
+    match replied_content.map(|c| snippet(c, REPLY_SNIPPET_LENGTH)) {
+        Some(snippet) if !snippet.is_empty() => {
+            let quote = format!(
+                "{}{}",
+                renderer.paragraph(format!("{}:", reply_link).as_str()),
+                renderer.paragraph(renderer.text(snippet.as_str()).as_str())
+            );
+            renderer.quote(quote.as_str())
+        }
+        _ => renderer.paragraph(renderer.wrap(&Entity::Italic, reply_link.as_str()).as_str()),
+    }
+}
 
-                let ids = self.inner.db.get_channel_post_ids(saved_channel.id, HISTORY_LIMIT).await?;
-                for (id, telegram_id) in ids.into_iter() {
-                    if let Some(files) = post_to_files.remove(&telegram_id) {
-                        self.inner.db.save_post_files(id, files).await?;
-                    }
-                }
+/// Link preview rendered as a quote under the post text.
+fn web_page_card(renderer: &dyn Renderer, web_page: &WebPage, photo_url: Option<String>) -> String {
+    let mut card = String::new();
+    if let Some(site_name) = &web_page.site_name {
+        let site_name = renderer.wrap(&Entity::Bold, renderer.text(site_name).as_str());
+        card.push_str(renderer.paragraph(site_name.as_str()).as_str());
+    }
+    let title = web_page
+        .title
+        .as_deref()
+        .unwrap_or(web_page.display_url.as_str());
+    let link = renderer.wrap(
+        &Entity::TextUrl(web_page.url.clone()),
+        renderer.text(title).as_str(),
+    );
+    card.push_str(renderer.paragraph(link.as_str()).as_str());
+    if let Some(description) = &web_page.description {
+        let description = renderer.text(description);
+        card.push_str(renderer.paragraph(description.as_str()).as_str());
+    }
+    let image = photo_url
+        .map(|url| renderer.image(url.as_str(), title))
+        .unwrap_or_default();
+    if !image.is_empty() {
+        card.push_str(renderer.paragraph(image.as_str()).as_str());
+    }
+    renderer.quote(card.as_str())
+}
 
+/// Title made of post text according to the title policy.
+fn derive_title(settings: &FeedSettings, post: &Post) -> Option<String> {
+    // summary would bring in link targets of text urls
+    let text = match &post.formatted_text {
+        Some(formatted_text) => formatted_text.text().as_str(),
+        None => post.summary().unwrap_or_default(),
+    }
+    .trim();
+    if let Some(web_page_title) = web_page_title(post, text).filter(|_| settings.web_page_titles) {
+        return Some(truncate(web_page_title, settings.title_length));
+    }
+    let title = match settings.title_policy {
+        TitlePolicy::None => return None,
+        TitlePolicy::Line => first_line(text).to_string(),
+        TitlePolicy::Sentence => first_sentence(first_line(text)).to_string(),
+        TitlePolicy::Chars => text.to_string(),
+        TitlePolicy::Bold => post
+            .formatted_text
+            .as_ref()
+            .and_then(first_bold)
+            .unwrap_or_else(|| first_line(text).to_string()),
+    };
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    Some(truncate(title.as_str(), settings.title_length)).filter(|t| !t.is_empty())
+}
 
-                Ok(Some(saved_channel))
-            }
+/// Title of the link preview, for posts consisting of the link only.
+fn web_page_title<'a>(post: &'a Post, text: &str) -> Option<&'a str> {
+    let web_page = post.web_page()?;
+    let link = text.trim_end_matches('/');
+    let link_only = link == web_page.url.trim_end_matches('/')
+        || link == web_page.display_url.trim_end_matches('/');
+    match link_only {
+        true => web_page.title.as_deref(),
+        false => None,
+    }
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or_default()
+}
+
+/// Text up to the first sentence terminator followed by whitespace.
+fn first_sentence(text: &str) -> &str {
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, ch)) = chars.next() {
+        let at_boundary = chars.peek().map(|(_, next)| next.is_whitespace());
+        if matches!(ch, '.' | '!' | '?' | '…') && at_boundary.unwrap_or(true) {
+            return &text[..i + ch.len_utf8()];
         }
     }
+    text
+}
+
+/// Cuts html content to plain text of `max_chars` characters.
+fn snippet(html: &str, max_chars: usize) -> String {
+    truncate(strip_html(html).trim(), max_chars)
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(max_chars).collect();
+    format!("{}…", cut.trim_end())
 }
 
-fn rss_err<E: std::fmt::Debug>(err: E) -> anyhow::Error {
-    anyhow::anyhow!("error building rss feed: {:?}", err)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_tdlib::types::FormattedText;
+
+    fn post_with_link(text: &str, offset: i32, length: i32) -> Post {
+        let json = serde_json::json!({
+            "@type": "formattedText",
+            "text": text,
+            "entities": [{
+                "@type": "textEntity",
+                "offset": offset,
+                "length": length,
+                "type": {"@type": "textEntityTypeTextUrl", "url": "https://example.com/a.b"},
+            }],
+        });
+        let formatted_text = FormattedText::from_json(json.to_string()).unwrap();
+        Post {
+            title: None,
+            link: post_link("channel", 1),
+            telegram_id: 1,
+            pub_date: 100,
+            content: text.to_string(),
+            chat_id: -100,
+            files: vec![],
+            edit_date: None,
+            deleted_at: None,
+            media_album_id: None,
+            forwarded_from: None,
+            reply_to: None,
+            summary: Some(render_formatted_text(
+                &formatted_text,
+                &PlainRenderer,
+                Some("channel"),
+            )),
+            formatted_text: Some(formatted_text),
+            web_page: None,
+        }
+    }
+
+    #[test]
+    fn titles_leave_out_link_targets() {
+        let post = post_with_link("Read the docs\nThey are new", 9, 4);
+        let settings = FeedSettings {
+            title_policy: TitlePolicy::Line,
+            ..FeedSettings::default()
+        };
+        assert_eq!(
+            derive_title(&settings, &post).as_deref(),
+            Some("Read the docs")
+        );
+        let post = post_with_link("See the docs. They are new", 8, 4);
+        let settings = FeedSettings {
+            title_policy: TitlePolicy::Sentence,
+            ..FeedSettings::default()
+        };
+        assert_eq!(
+            derive_title(&settings, &post).as_deref(),
+            Some("See the docs.")
+        );
+    }
+
+    #[test]
+    fn first_line_skips_blank_lines() {
+        assert_eq!(first_line("\n  \n  Hello there \nsecond"), "Hello there");
+        assert_eq!(first_line(""), "");
+    }
+
+    #[test]
+    fn first_sentence_ends_at_terminator_before_whitespace() {
+        assert_eq!(
+            first_sentence("Version 1.2 is out! Get it"),
+            "Version 1.2 is out!"
+        );
+        assert_eq!(first_sentence("Wait… what? yes"), "Wait…");
+        assert_eq!(first_sentence("Done."), "Done.");
+        assert_eq!(first_sentence("no terminator"), "no terminator");
+    }
+
+    #[test]
+    fn truncate_cuts_by_chars_with_ellipsis() {
+        assert_eq!(truncate("short", 5), "short");
+        assert_eq!(truncate("Привет, мир", 7), "Привет,…");
+        assert_eq!(truncate("two words", 4), "two…");
+    }
 }
@@ -1,28 +1,94 @@
-use crate::models::{File, TelegramChatId, TelegramPostId};
+use crate::models::{
+    post_link, EditedPost, File, ForwardedFrom, TelegramChatId, TelegramPostId,
+};
 pub use crate::models::{Channel, NewChannel, Post};
-use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
 use sqlx::Row;
 use std::collections::HashMap;
+use std::str::FromStr;
+
+static MIGRATOR: Migrator = sqlx::migrate!();
 
 pub struct DbService {
     pool: SqlitePool,
 }
 
+/// Embedded migration and whether it's already applied to the database.
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+}
+
 impl DbService {
+    /// Connects to the database, creating it if missing, and applies pending migrations.
     pub async fn new(db_path: &str) -> anyhow::Result<Self> {
+        let db = Self::connect(db_path).await?;
+        db.migrate().await?;
+        Ok(db)
+    }
+
+    /// Connects to the database, creating it if missing, without touching the schema.
+    pub async fn connect(db_path: &str) -> anyhow::Result<Self> {
+        let options = SqliteConnectOptions::from_str(db_path)?
+            .create_if_missing(true)
+            .foreign_keys(true);
         let pool = SqlitePoolOptions::new()
             .max_connections(3)
-            .connect(db_path)
+            .connect_with(options)
             .await?;
         Ok(Self { pool })
     }
 
+    pub async fn migrate(&self) -> anyhow::Result<()> {
+        MIGRATOR.run(&self.pool).await?;
+        Ok(())
+    }
+
+    pub async fn migration_statuses(&self) -> anyhow::Result<Vec<MigrationStatus>> {
+        let migrations_table = sqlx::query(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        let applied: Vec<i64> = match migrations_table {
+            None => Vec::new(),
+            Some(_) => sqlx::query("SELECT version FROM _sqlx_migrations WHERE success = 1")
+                .fetch_all(&self.pool)
+                .await?
+                .iter()
+                .map(|r| r.get("version"))
+                .collect(),
+        };
+        Ok(MIGRATOR
+            .iter()
+            .map(|m| MigrationStatus {
+                version: m.version,
+                description: m.description.to_string(),
+                applied: applied.contains(&m.version),
+            })
+            .collect())
+    }
+
     pub async fn save_file(&self, file: &File) -> anyhow::Result<()> {
         sqlx::query!(
             r#"
-            INSERT INTO files (local_path, remote_file, remote_id) VALUES ($1, $2, $3)
-            ON CONFLICT(remote_file) DO UPDATE SET remote_id = excluded.remote_id, local_path=excluded.local_path"#,
-            file.local_path, file.remote_file, file.remote_id
+            INSERT INTO files (local_path, remote_file, remote_id, mime_type, size, file_name, duration, thumbnail,
+                width, height)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            ON CONFLICT(remote_file) DO UPDATE SET remote_id = excluded.remote_id,
+                local_path = coalesce(excluded.local_path, files.local_path),
+                mime_type = coalesce(excluded.mime_type, files.mime_type),
+                size = excluded.size,
+                file_name = coalesce(excluded.file_name, files.file_name),
+                duration = coalesce(excluded.duration, files.duration),
+                thumbnail = coalesce(excluded.thumbnail, files.thumbnail),
+                width = coalesce(excluded.width, files.width),
+                height = coalesce(excluded.height, files.height)"#,
+            file.local_path, file.remote_file, file.remote_id, file.mime_type, file.size,
+            file.file_name, file.duration, file.thumbnail, file.width, file.height
         )
             .execute(&self.pool)
             .await?;
@@ -30,38 +96,58 @@ impl DbService {
     }
 
     pub async fn get_file(&self, file: &File) -> anyhow::Result<Option<File>> {
+        self.get_file_by_remote_id(file.remote_id.as_str()).await
+    }
+
+    pub async fn get_file_by_remote_id(&self, remote_id: &str) -> anyhow::Result<Option<File>> {
         Ok(sqlx::query_as!(
             File,
-            r#"SELECT local_path, remote_file as "remote_file: i32", remote_id FROM files WHERE remote_id = $1"#,
-            file.remote_id
+            r#"SELECT local_path, remote_file as "remote_file: i32", remote_id, mime_type, size, file_name, duration as "duration: i32", thumbnail,
+                width as "width: i32", height as "height: i32" FROM files WHERE remote_id = $1"#,
+            remote_id
         )
             .fetch_optional(&self.pool)
             .await?)
     }
 
+    /// Files of every post in order of its messages, then in order they were attached.
     pub async fn get_files_for_posts(
         &self,
-        post_ids: Vec<i32>,
-    ) -> anyhow::Result<HashMap<i32, Vec<i32>>> {
-        let rows = sqlx::query(
-            r#"SELECT post_id, local_path, remote_file as "remote_file: i32", remote_id
-                FROM files
-                INNER JOIN post_files ON post_files.file_id=files.id
-                WHERE post_files.post_id IN ($1)"#,
-        )
-        .bind(
-            post_ids
-                .iter()
-                .map(ToString::to_string)
-                .collect::<Vec<String>>()
-                .join(","),
-        )
-        .fetch_all(&self.pool)
-        .await?;
-        let mut result = HashMap::with_capacity(rows.len());
+        post_ids: &[i64],
+    ) -> anyhow::Result<HashMap<i64, Vec<File>>> {
+        if post_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let sql = format!(
+            r#"SELECT post_files.post_id, files.local_path, files.remote_file, files.remote_id,
+                files.mime_type, files.size, files.file_name, files.duration, files.thumbnail,
+                files.width, files.height
+                FROM post_files
+                INNER JOIN files ON files.id = post_files.file_id
+                WHERE post_files.post_id IN ({})
+                ORDER BY post_files.message_id, post_files.id"#,
+            vec!["?"; post_ids.len()].join(", ")
+        );
+        let mut query = sqlx::query(sql.as_str());
+        for post_id in post_ids.iter() {
+            query = query.bind(post_id);
+        }
+        let rows = query.fetch_all(&self.pool).await?;
+        let mut result = HashMap::with_capacity(post_ids.len());
         for row in rows.into_iter() {
-            let mut post_files: &mut Vec<i32> = result.entry(row.get("post_id")).or_default();
-            post_files.push(row.get("remote_file"))
+            let post_files: &mut Vec<File> = result.entry(row.get("post_id")).or_default();
+            post_files.push(File {
+                local_path: row.get("local_path"),
+                remote_file: row.get("remote_file"),
+                remote_id: row.get("remote_id"),
+                mime_type: row.get("mime_type"),
+                size: row.get("size"),
+                file_name: row.get("file_name"),
+                duration: row.get("duration"),
+                thumbnail: row.get("thumbnail"),
+                width: row.get("width"),
+                height: row.get("height"),
+            })
         }
         Ok(result)
     }
@@ -69,112 +155,656 @@ impl DbService {
     pub async fn get_not_loaded_files(&self) -> anyhow::Result<Vec<File>> {
         Ok(sqlx::query_as!(
             File,
-            r#"SELECT local_path, remote_file as "remote_file: i32", remote_id FROM files WHERE local_path is null"#,
+            r#"SELECT local_path, remote_file as "remote_file: i32", remote_id, mime_type, size, file_name, duration as "duration: i32", thumbnail,
+                width as "width: i32", height as "height: i32" FROM files WHERE local_path is null"#,
         )
             .fetch_all(&self.pool)
             .await?)
     }
 
-    pub async fn save_post_files(&self, post_id: i64, file_ids: Vec<i32>) -> anyhow::Result<()> {
-        for file_id in file_ids.iter() {
+    /// Links already saved files of the `message_id` message to the post,
+    /// files linked before are skipped.
+    pub async fn save_post_files(
+        &self,
+        post_id: i64,
+        message_id: TelegramPostId,
+        remote_files: Vec<i32>,
+    ) -> anyhow::Result<()> {
+        for remote_file in remote_files.iter() {
             sqlx::query!(
-                "insert into post_files (post_id, file_id) values ($1, $2)",
-                post_id, file_id
-            ).execute(&self.pool).await?;
+                r#"INSERT INTO post_files (post_id, file_id, message_id)
+                SELECT $1, id, $3 FROM files WHERE remote_file = $2
+                ON CONFLICT(post_id, file_id) DO NOTHING"#,
+                post_id,
+                remote_file,
+                message_id
+            )
+            .execute(&self.pool)
+            .await?;
         }
         Ok(())
     }
 
     pub async fn save_channel(&self, channel: NewChannel) -> anyhow::Result<()> {
+        let photo_id = channel.photo.as_ref().map(|p| p.remote_id.clone());
+        // username may have passed from another channel
+        sqlx::query!(
+            "UPDATE channels SET username = '' WHERE username = $1 AND telegram_id != $2",
+            channel.username,
+            channel.telegram_id,
+        )
+        .execute(&self.pool)
+        .await?;
         sqlx::query_as!(
             Channel,
-            r#"INSERT INTO channels (title, username, telegram_id)
-            VALUES ($1, $2, $3)
-            ON CONFLICT(username) DO UPDATE SET title = excluded.title, telegram_id=excluded.telegram_id"#,
+            r#"INSERT INTO channels (title, username, telegram_id, description, member_count, photo)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT(telegram_id) DO UPDATE SET title = excluded.title, username = excluded.username,
+                description = excluded.description, member_count = excluded.member_count, photo = excluded.photo"#,
             channel.title,
             channel.username,
             channel.telegram_id,
+            channel.description,
+            channel.member_count,
+            photo_id,
         )
         .execute(&self.pool)
         .await?;
+        if let Some(photo) = &channel.photo {
+            self.save_file(photo).await?;
+        }
         Ok(())
     }
 
+    /// Inserts new posts and refreshes content of already saved ones.
+    /// Messages of a media album are merged into a single post.
     pub async fn save_channel_posts(&self, posts: &Vec<Post>) -> anyhow::Result<()> {
-        for p in posts.iter() {
+        let mut posts: Vec<&Post> = posts.iter().collect();
+        // album post takes id and date of its earliest message
+        posts.sort_by_key(|p| p.telegram_id);
+        for p in posts.into_iter() {
+            for f in p.files.iter() {
+                self.save_file(f).await?;
+            }
+            let post_id = match self.get_album_post_id(p).await? {
+                Some(post_id) => post_id,
+                None => self.save_post(p).await?,
+            };
+            let formatted_text = to_json(&p.formatted_text)?;
             sqlx::query!(
-                r#"INSERT INTO posts (title, link, telegram_id, pub_date, content, chat_id)
-                VALUES ($1, $2, $3, $4, $5, $6)"#,
-                p.title,
-                p.link,
+                r#"INSERT INTO post_messages (post_id, chat_id, telegram_id, content, formatted_text)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT(chat_id, telegram_id) DO UPDATE SET post_id = excluded.post_id,
+                    content = excluded.content, formatted_text = excluded.formatted_text"#,
+                post_id,
+                p.chat_id,
                 p.telegram_id,
-                p.pub_date,
                 p.content,
-                p.chat_id
+                formatted_text,
             )
             .execute(&self.pool)
             .await?;
+            self.save_post_files(
+                post_id,
+                p.telegram_id,
+                p.files.iter().map(|f| f.remote_file).collect(),
+            )
+            .await?;
+            if p.media_album_id.is_some() {
+                self.update_post_caption(post_id).await?;
+            }
         }
         Ok(())
     }
 
+    /// Inserts or refreshes the post of a single message, returns its id.
+    async fn save_post(&self, p: &Post) -> anyhow::Result<i64> {
+        let forward_from = p.forwarded_from.as_ref().and_then(|f| f.name.clone());
+        let forward_link = p.forwarded_from.as_ref().and_then(|f| f.link.clone());
+        let formatted_text = to_json(&p.formatted_text)?;
+        let web_page = to_json(&p.web_page)?;
+        sqlx::query!(
+            r#"INSERT INTO posts (title, link, telegram_id, pub_date, content, chat_id, edit_date, media_album_id,
+                forward_from, forward_link, reply_to, formatted_text, web_page)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+            ON CONFLICT(chat_id, telegram_id) DO UPDATE SET title = coalesce(excluded.title, posts.title),
+                link = coalesce(nullif(excluded.link, ''), posts.link), content = excluded.content,
+                edit_date = coalesce(excluded.edit_date, posts.edit_date), media_album_id = excluded.media_album_id,
+                forward_from = excluded.forward_from, forward_link = excluded.forward_link,
+                reply_to = excluded.reply_to, formatted_text = excluded.formatted_text,
+                web_page = excluded.web_page"#,
+            p.title,
+            p.link,
+            p.telegram_id,
+            p.pub_date,
+            p.content,
+            p.chat_id,
+            p.edit_date,
+            p.media_album_id,
+            forward_from,
+            forward_link,
+            p.reply_to,
+            formatted_text,
+            web_page
+        )
+        .execute(&self.pool)
+        .await?;
+        let saved = sqlx::query!(
+            "SELECT id FROM posts WHERE chat_id = $1 AND telegram_id = $2",
+            p.chat_id,
+            p.telegram_id,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(saved.id)
+    }
+
+    /// Already saved post of an earlier message of the same media album.
+    async fn get_album_post_id(&self, post: &Post) -> anyhow::Result<Option<i64>> {
+        let media_album_id = match post.media_album_id {
+            None => return Ok(None),
+            Some(id) => id,
+        };
+        let saved = sqlx::query!(
+            r#"SELECT id FROM posts
+            WHERE chat_id = $1 AND media_album_id = $2 AND telegram_id < $3
+            ORDER BY telegram_id
+            LIMIT 1"#,
+            post.chat_id,
+            media_album_id,
+            post.telegram_id,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(saved.map(|s| s.id))
+    }
+
+    /// Post shows the earliest non-empty caption of its messages.
+    async fn update_post_caption(&self, post_id: i64) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"UPDATE posts SET (content, formatted_text) = (
+                SELECT content, formatted_text FROM post_messages
+                WHERE post_id = $1
+                ORDER BY content = '', telegram_id
+                LIMIT 1)
+            WHERE id = $1"#,
+            post_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Applies message edit to the post the message belongs to.
+    pub async fn update_post(&self, post: &EditedPost) -> anyhow::Result<()> {
+        let saved = sqlx::query!(
+            "SELECT post_id FROM post_messages WHERE chat_id = $1 AND telegram_id = $2",
+            post.chat_id,
+            post.telegram_id,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        let post_id = match saved {
+            None => return Ok(()),
+            Some(saved) => saved.post_id,
+        };
+        sqlx::query!(
+            "UPDATE posts SET edit_date = coalesce($1, edit_date) WHERE id = $2",
+            post.edit_date,
+            post_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        // only content updates carry text and media, which replace the old ones
+        let content = match &post.content {
+            None => return Ok(()),
+            Some(content) => content,
+        };
+        let formatted_text = to_json(&post.formatted_text)?;
+        let web_page = to_json(&post.web_page)?;
+        sqlx::query!(
+            r#"UPDATE post_messages SET content = $1, formatted_text = $2
+            WHERE chat_id = $3 AND telegram_id = $4"#,
+            content,
+            formatted_text,
+            post.chat_id,
+            post.telegram_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query!(
+            "UPDATE posts SET web_page = $1 WHERE id = $2",
+            web_page,
+            post_id
+        )
+        .execute(&self.pool)
+        .await?;
+        self.update_post_caption(post_id).await?;
+        for f in post.files.iter() {
+            self.save_file(f).await?;
+        }
+        sqlx::query!(
+            "DELETE FROM post_files WHERE post_id = $1 AND message_id = $2",
+            post_id,
+            post.telegram_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        self.save_post_files(
+            post_id,
+            post.telegram_id,
+            post.files.iter().map(|f| f.remote_file).collect(),
+        )
+        .await
+    }
+
     pub async fn get_channel(&self, channel_name: &str) -> anyhow::Result<Option<Channel>> {
         Ok(sqlx::query_as!(
             Channel,
-            "SELECT id, title, username, telegram_id from channels where username = $1",
+            "SELECT id, title, username, telegram_id, description, member_count, photo from channels where username = $1",
             channel_name
         )
         .fetch_optional(&self.pool)
         .await?)
     }
 
-    pub async fn get_channel_post_ids(&self, chat_id: TelegramChatId, limit: i32) -> anyhow::Result<Vec<(i64, TelegramPostId)>> {
-        let rows = sqlx::query!(
-            r#"SELECT id, telegram_id
-            FROM posts
-            WHERE chat_id = $1
-            LIMIT $2"#,
-            chat_id,
-            limit,
-        ).fetch_all(&self.pool).await?;
-        Ok(rows.into_iter().map(|v|(v.id, v.telegram_id)).collect())
+    pub async fn get_channel_by_telegram_id(
+        &self,
+        telegram_id: TelegramChatId,
+    ) -> anyhow::Result<Option<Channel>> {
+        Ok(sqlx::query_as!(
+            Channel,
+            "SELECT id, title, username, telegram_id, description, member_count, photo from channels where telegram_id = $1",
+            telegram_id
+        )
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    /// Content of the channel posts by their telegram ids, missing posts are skipped,
+    /// as well as deleted ones unless `include_deleted` is set.
+    pub async fn get_posts_content(
+        &self,
+        chat_id: TelegramChatId,
+        telegram_ids: &[TelegramPostId],
+        include_deleted: bool,
+    ) -> anyhow::Result<HashMap<TelegramPostId, String>> {
+        if telegram_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let sql = format!(
+            r#"SELECT post_messages.telegram_id, posts.content
+            FROM post_messages
+            INNER JOIN posts ON posts.id = post_messages.post_id
+            WHERE post_messages.chat_id = ? AND (? OR posts.deleted_at IS NULL)
+                AND post_messages.telegram_id IN ({})"#,
+            vec!["?"; telegram_ids.len()].join(", ")
+        );
+        let mut query = sqlx::query(sql.as_str())
+            .bind(chat_id)
+            .bind(include_deleted);
+        for telegram_id in telegram_ids.iter() {
+            query = query.bind(telegram_id);
+        }
+        let rows = query.fetch_all(&self.pool).await?;
+        Ok(rows
+            .into_iter()
+            .map(|r| (r.get("telegram_id"), r.get("content")))
+            .collect())
     }
 
+    pub async fn mark_posts_deleted(
+        &self,
+        chat_id: TelegramChatId,
+        telegram_ids: &[TelegramPostId],
+    ) -> anyhow::Result<()> {
+        for telegram_id in telegram_ids.iter() {
+            sqlx::query!(
+                r#"UPDATE post_messages SET deleted_at = strftime('%s', 'now')
+                WHERE chat_id = $1 AND telegram_id = $2 AND deleted_at IS NULL"#,
+                chat_id,
+                telegram_id,
+            )
+            .execute(&self.pool)
+            .await?;
+            // album post is deleted along with its last message
+            sqlx::query!(
+                r#"UPDATE posts SET deleted_at = strftime('%s', 'now')
+                WHERE deleted_at IS NULL
+                    AND id IN (SELECT post_id FROM post_messages WHERE chat_id = $1 AND telegram_id = $2)
+                    AND NOT EXISTS (SELECT 1 FROM post_messages
+                        WHERE post_messages.post_id = posts.id AND post_messages.deleted_at IS NULL)"#,
+                chat_id,
+                telegram_id,
+            )
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Newest posts of the channel, published before `before` post if it's set.
+    /// With `audio_only` posts without audio files are skipped, so podcast pages stay full.
     pub async fn get_channel_posts(
         &self,
         channel_name: &str,
+        include_deleted: bool,
+        audio_only: bool,
+        before: Option<TelegramPostId>,
+        limit: i32,
     ) -> anyhow::Result<Option<(Channel, Vec<Post>)>> {
         let ch = match self.get_channel(channel_name).await? {
             None => return Ok(None),
             Some(ch) => ch,
         };
         let rows = sqlx::query(
-            r#"SELECT title, link, telegram_id, pub_date as "pub_date: i32", content, chat_id
+            r#"SELECT id, title, link, telegram_id, pub_date, content, chat_id, edit_date,
+                deleted_at, media_album_id, forward_from, forward_link, reply_to, formatted_text,
+                web_page
             FROM posts
-            WHERE chat_id = $1
-            LIMIT 25"#,
-            // ch.telegram_id
+            WHERE chat_id = $1 AND ($2 OR deleted_at IS NULL) AND ($3 IS NULL OR telegram_id < $3)
+                AND (NOT $5 OR EXISTS (
+                    SELECT 1 FROM post_files
+                    INNER JOIN files ON files.id = post_files.file_id
+                    WHERE post_files.post_id = posts.id AND files.mime_type LIKE 'audio/%'))
+            ORDER BY pub_date DESC, telegram_id DESC
+            LIMIT $4"#,
         )
         .bind(ch.telegram_id)
+        .bind(include_deleted)
+        .bind(before)
+        .bind(limit)
+        .bind(audio_only)
         .fetch_all(&self.pool)
         .await?;
-        let mut files = self
-            .get_files_for_posts(rows.iter().map(|r| r.get("id")).collect())
-            .await?;
+        let post_ids: Vec<i64> = rows.iter().map(|r| r.get("id")).collect();
+        let mut files = self.get_files_for_posts(&post_ids).await?;
         let mut posts = Vec::with_capacity(rows.len());
-        rows.into_iter().for_each(|r| {
-            let post_files = files.remove(&r.get("id")).unwrap_or_default();
+        for r in rows.into_iter() {
+            let post_files = files.remove(&r.get::<i64, _>("id")).unwrap_or_default();
+            let link: String = r.get("link");
+            let telegram_id = r.get("telegram_id");
             let post = Post {
                 title: r.get("title"),
-                link: r.get("link"),
-                telegram_id: r.get("telegram_id"),
+                link: match link.is_empty() {
+                    // posts saved before links were introduced
+                    true => post_link(ch.username.as_str(), telegram_id),
+                    false => link,
+                },
+                telegram_id,
                 pub_date: r.get("pub_date"),
                 content: r.get("content"),
                 chat_id: r.get("chat_id"),
                 files: post_files,
+                edit_date: r.get("edit_date"),
+                deleted_at: r.get("deleted_at"),
+                media_album_id: r.get("media_album_id"),
+                forwarded_from: r.get::<Option<String>, _>("forward_from").map(|name| {
+                    ForwardedFrom {
+                        name: Some(name),
+                        link: r.get("forward_link"),
+                        ..Default::default()
+                    }
+                }),
+                reply_to: r.get("reply_to"),
+                formatted_text: from_json(r.get("formatted_text"))?,
+                summary: None,
+                web_page: from_json(r.get("web_page"))?,
             };
             posts.push(post);
-        });
+        }
         Ok(Some((ch, posts)))
     }
 }
+
+fn to_json<T: serde::Serialize>(value: &Option<T>) -> anyhow::Result<Option<String>> {
+    Ok(value.as_ref().map(serde_json::to_string).transpose()?)
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(value: Option<String>) -> anyhow::Result<Option<T>> {
+    Ok(value
+        .map(|v| serde_json::from_str(v.as_str()))
+        .transpose()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// In-memory database lives as long as its only connection.
+    async fn memory_db() -> DbService {
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")
+            .unwrap()
+            .foreign_keys(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .unwrap();
+        MIGRATOR.run(&pool).await.unwrap();
+        DbService { pool }
+    }
+
+    fn file(remote_file: i32, mime_type: &str) -> File {
+        File {
+            local_path: None,
+            remote_file,
+            remote_id: format!("remote-{}", remote_file),
+            mime_type: None,
+            size: remote_file as i64 * 1000,
+            file_name: None,
+            duration: None,
+            thumbnail: None,
+            width: None,
+            height: None,
+        }
+        .with_mime_type(mime_type)
+    }
+
+    fn post(telegram_id: TelegramPostId, pub_date: i32, files: Vec<File>) -> Post {
+        Post {
+            title: None,
+            link: post_link("channel", telegram_id),
+            telegram_id,
+            pub_date,
+            content: format!("post {}", telegram_id),
+            chat_id: -100,
+            files,
+            edit_date: None,
+            deleted_at: None,
+            media_album_id: None,
+            forwarded_from: None,
+            reply_to: None,
+            formatted_text: None,
+            summary: None,
+            web_page: None,
+        }
+    }
+
+    fn channel(telegram_id: TelegramChatId, username: &str) -> NewChannel {
+        NewChannel {
+            title: "Channel".to_string(),
+            telegram_id,
+            username: username.to_string(),
+            description: "".to_string(),
+            member_count: 1,
+            photo: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn private_channels_and_reused_usernames_are_saved() {
+        let db = memory_db().await;
+        db.save_channel(channel(-100, "")).await.unwrap();
+        db.save_channel(channel(-200, "")).await.unwrap();
+        db.save_channel(channel(-300, "channel")).await.unwrap();
+        // the username passed to another channel
+        db.save_channel(channel(-400, "channel")).await.unwrap();
+
+        let saved = db.get_channel("channel").await.unwrap().unwrap();
+        assert_eq!(saved.telegram_id, -400);
+        let previous = db.get_channel_by_telegram_id(-300).await.unwrap().unwrap();
+        assert_eq!(previous.username, "");
+        assert!(db.get_channel_by_telegram_id(-200).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn channel_posts_have_files_in_attach_order() {
+        let db = memory_db().await;
+        db.save_channel(channel(-100, "channel")).await.unwrap();
+        let photo = file(1, "image/jpeg").with_dimensions(800, 600);
+        let audio = file(2, "audio/mpeg").with_duration(60);
+        let video = file(3, "video/mp4").with_thumbnail(&photo);
+        // the second post attaches files in order other than they were saved
+        let posts = vec![
+            post(1 << 20, 100, vec![photo.clone(), audio.clone()]),
+            post(2 << 20, 200, vec![video.clone(), photo.clone()]),
+            post(3 << 20, 300, vec![]),
+        ];
+        db.save_channel_posts(&posts).await.unwrap();
+        // saving again must not duplicate files of the posts
+        db.save_channel_posts(&posts).await.unwrap();
+
+        let (channel, posts) = db
+            .get_channel_posts("channel", false, false, None, 10)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(channel.telegram_id, -100);
+        let ids: Vec<TelegramPostId> = posts.iter().map(|p| p.telegram_id).collect();
+        assert_eq!(ids, vec![3 << 20, 2 << 20, 1 << 20]);
+        assert_eq!(posts[0].files, vec![]);
+        assert_eq!(posts[1].files, vec![video, photo.clone()]);
+        assert_eq!(posts[2].files, vec![photo, audio]);
+    }
+
+    #[tokio::test]
+    async fn audio_only_pages_skip_posts_without_audio() {
+        let db = memory_db().await;
+        db.save_channel(channel(-100, "channel")).await.unwrap();
+        let audio = file(1, "audio/mpeg");
+        let posts = vec![
+            post(1 << 20, 100, vec![audio.clone()]),
+            post(2 << 20, 200, vec![file(2, "image/jpeg")]),
+            post(3 << 20, 300, vec![]),
+            post(4 << 20, 400, vec![file(3, "audio/ogg")]),
+        ];
+        db.save_channel_posts(&posts).await.unwrap();
+
+        let (_, posts) = db
+            .get_channel_posts("channel", false, true, Some(4 << 20), 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].telegram_id, 1 << 20);
+        assert_eq!(posts[0].files, vec![audio]);
+    }
+
+    #[tokio::test]
+    async fn media_edit_replaces_post_files() {
+        let db = memory_db().await;
+        db.save_channel(channel(-100, "channel")).await.unwrap();
+        db.save_channel_posts(&vec![post(1 << 20, 100, vec![file(1, "image/jpeg")])])
+            .await
+            .unwrap();
+        let video = file(2, "video/mp4");
+        let edit = EditedPost {
+            telegram_id: 1 << 20,
+            chat_id: -100,
+            content: Some("edited".to_string()),
+            formatted_text: None,
+            web_page: None,
+            files: vec![video.clone()],
+            edit_date: None,
+        };
+        db.update_post(&edit).await.unwrap();
+        db.update_post(&edit).await.unwrap();
+
+        let (_, posts) = db
+            .get_channel_posts("channel", false, false, None, 10)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(posts[0].content, "edited");
+        assert_eq!(posts[0].files, vec![video]);
+    }
+
+    #[tokio::test]
+    async fn deleted_replied_posts_have_no_content() {
+        let db = memory_db().await;
+        db.save_channel(channel(-100, "channel")).await.unwrap();
+        let posts = vec![post(1 << 20, 100, vec![]), post(2 << 20, 200, vec![])];
+        db.save_channel_posts(&posts).await.unwrap();
+        db.mark_posts_deleted(-100, &[1 << 20]).await.unwrap();
+
+        let ids = [1 << 20, 2 << 20, 3 << 20];
+        let visible = db.get_posts_content(-100, &ids, false).await.unwrap();
+        assert_eq!(visible.len(), 1);
+        assert_eq!(visible[&(2 << 20)], "post 2097152");
+        let all = db.get_posts_content(-100, &ids, true).await.unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[&(1 << 20)], "post 1048576");
+    }
+
+    #[tokio::test]
+    async fn album_edits_and_deletes_go_through_its_messages() {
+        let db = memory_db().await;
+        db.save_channel(channel(-100, "channel")).await.unwrap();
+        let album: Vec<Post> = (1..=3i64)
+            .rev()
+            .map(|i| Post {
+                content: if i == 2 { "caption" } else { "" }.to_string(),
+                media_album_id: Some(7),
+                ..post(i << 20, 100, vec![file(i as i32, "image/jpeg")])
+            })
+            .collect();
+        db.save_channel_posts(&album).await.unwrap();
+        let edit = |telegram_id: TelegramPostId, files: Vec<File>| EditedPost {
+            telegram_id,
+            chat_id: -100,
+            content: Some("".to_string()),
+            formatted_text: None,
+            web_page: None,
+            files,
+            edit_date: Some(200),
+        };
+        // media-only edits keep the caption and replace only the edited message files
+        db.update_post(&edit(3 << 20, vec![file(4, "video/mp4")]))
+            .await
+            .unwrap();
+        db.update_post(&edit(1 << 20, vec![file(1, "image/jpeg")]))
+            .await
+            .unwrap();
+
+        let (_, posts) = db
+            .get_channel_posts("channel", false, false, None, 10)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].telegram_id, 1 << 20);
+        assert_eq!(posts[0].content, "caption");
+        assert_eq!(posts[0].edit_date, Some(200));
+        let remote_files: Vec<i32> = posts[0].files.iter().map(|f| f.remote_file).collect();
+        assert_eq!(remote_files, vec![1, 2, 4]);
+
+        // album stays visible until all of its messages are deleted
+        db.mark_posts_deleted(-100, &[1 << 20]).await.unwrap();
+        let (_, posts) = db
+            .get_channel_posts("channel", false, false, None, 10)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(posts.len(), 1);
+        db.mark_posts_deleted(-100, &[2 << 20, 3 << 20])
+            .await
+            .unwrap();
+        let (_, posts) = db
+            .get_channel_posts("channel", false, false, None, 10)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(posts.len(), 0);
+    }
+}
@@ -0,0 +1,89 @@
+use super::{attachments, file_url, mime_type, to_datetime};
+use crate::models::{Channel, File, Post};
+use crate::settings::{FeedSettings, TextFormat};
+use atom_syndication::{Content, Entry, Feed, Link, Person};
+
+pub fn build(
+    settings: &FeedSettings,
+    text_format: TextFormat,
+    channel: &Channel,
+    posts: &[Post],
+    next_url: Option<&str>,
+) -> Feed {
+    let mut author = Person::default();
+    author.set_name(channel.title.clone());
+    author.set_uri(Some(channel.link()));
+
+    // markdown has no atom content type of its own, readers show it as is
+    let content_type = match text_format {
+        TextFormat::Html => "html",
+        TextFormat::Markdown | TextFormat::Plain => "text",
+    };
+    let mut entries = Vec::with_capacity(posts.len());
+    for p in posts.iter() {
+        let mut content = Content::default();
+        content.set_content_type(Some(content_type.to_string()));
+        content.set_value(Some(p.content().to_string()));
+
+        let mut entry = Entry::default();
+        entry.set_id(entry_id(channel, p));
+        entry.set_title(p.title().clone().unwrap_or_default());
+        entry.set_updated(to_datetime(p.updated()));
+        entry.set_published(Some(to_datetime(p.pub_date())));
+        entry.set_authors(vec![author.clone()]);
+        entry.set_content(Some(content));
+        let mut links: Vec<Link> = attachments(p)
+            .map(|f| enclosure_link(settings, f))
+            .collect();
+        if !p.link().is_empty() {
+            links.insert(0, alternate_link(p.link()));
+        }
+        entry.set_links(links);
+        entries.push(entry);
+    }
+
+    let updated = posts.iter().map(Post::updated).max().unwrap_or_default();
+    let mut feed = Feed::default();
+    feed.set_id(format!("urn:tgfeed:{}", channel.telegram_id));
+    feed.set_title(channel.title.clone());
+    if !channel.description.is_empty() {
+        feed.set_subtitle(Some(channel.description.clone()));
+    }
+    if let Some(photo) = &channel.photo {
+        feed.set_logo(Some(file_url(settings, photo)));
+    }
+    feed.set_updated(to_datetime(updated));
+    let mut links = vec![alternate_link(channel.link().as_str())];
+    if let Some(next_url) = next_url {
+        let mut link = Link::default();
+        link.set_rel("next");
+        link.set_href(next_url);
+        links.push(link);
+    }
+    feed.set_links(links);
+    feed.set_authors(vec![author]);
+    feed.set_entries(entries);
+    feed
+}
+
+fn entry_id(channel: &Channel, post: &Post) -> String {
+    format!("urn:tgfeed:{}:{}", channel.telegram_id, post.telegram_id())
+}
+
+fn alternate_link(href: &str) -> Link {
+    let mut link = Link::default();
+    link.set_rel("alternate");
+    link.set_mime_type(Some("text/html".to_string()));
+    link.set_href(href);
+    link
+}
+
+fn enclosure_link(settings: &FeedSettings, file: &File) -> Link {
+    let mut link = Link::default();
+    link.set_rel("enclosure");
+    link.set_mime_type(Some(mime_type(file).to_string()));
+    link.set_length(Some(file.size.to_string()));
+    link.set_title(file.file_name.clone());
+    link.set_href(file_url(settings, &file.remote_id));
+    link
+}
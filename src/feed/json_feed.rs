@@ -0,0 +1,115 @@
+use super::{attachments, file_url, mime_type, to_datetime};
+use crate::models::{Channel, Post};
+use crate::settings::{FeedSettings, TextFormat};
+use serde::Serialize;
+
+const VERSION: &str = "https://jsonfeed.org/version/1.1";
+
+#[derive(Debug, Serialize)]
+pub struct JsonFeed {
+    version: &'static str,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    home_page_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    authors: Vec<Author>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_url: Option<String>,
+    items: Vec<Item>,
+}
+
+#[derive(Debug, Serialize)]
+struct Author {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Item {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    external_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_html: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<String>,
+    date_published: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_modified: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Debug, Serialize)]
+struct Attachment {
+    url: String,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size_in_bytes: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_in_seconds: Option<i32>,
+}
+
+pub fn build(
+    settings: &FeedSettings,
+    text_format: TextFormat,
+    channel: &Channel,
+    posts: &[Post],
+    next_url: Option<&str>,
+) -> JsonFeed {
+    let items = posts
+        .iter()
+        .map(|p| Item {
+            id: p.telegram_id().to_string(),
+            url: Some(p.link().to_string()).filter(|l| !l.is_empty()),
+            external_url: p.web_page().map(|w| w.url.clone()),
+            title: p.title().clone(),
+            content_html: match text_format {
+                TextFormat::Html => Some(p.content().to_string()),
+                _ => None,
+            },
+            content_text: match text_format {
+                TextFormat::Html => None,
+                _ => Some(p.content().to_string()),
+            },
+            summary: p.summary().map(str::to_string),
+            date_published: to_datetime(p.pub_date()).to_rfc3339(),
+            date_modified: p.edit_date().map(|d| to_datetime(d).to_rfc3339()),
+            attachments: attachments(p)
+                .map(|f| Attachment {
+                    url: file_url(settings, &f.remote_id),
+                    mime_type: mime_type(f).to_string(),
+                    title: f.file_name.clone(),
+                    size_in_bytes: Some(f.size).filter(|s| *s > 0),
+                    duration_in_seconds: f.duration,
+                })
+                .collect(),
+        })
+        .collect();
+    JsonFeed {
+        version: VERSION,
+        title: channel.title.clone(),
+        home_page_url: Some(channel.link()),
+        description: Some(channel.description.clone()).filter(|d| !d.is_empty()),
+        icon: channel.photo.as_ref().map(|p| file_url(settings, p)),
+        authors: vec![Author {
+            name: channel.title.clone(),
+            url: Some(channel.link()),
+        }],
+        next_url: next_url.map(str::to_string),
+        items,
+    }
+}
@@ -0,0 +1,151 @@
+use crate::models::{Channel, File, Post, TelegramPostId};
+use crate::settings::{FeedSettings, TextFormat};
+use atom_syndication::FixedDateTime;
+use chrono::{FixedOffset, TimeZone};
+use std::str::FromStr;
+
+mod atom_feed;
+mod json_feed;
+mod podcast_feed;
+mod rss_feed;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeedFormat {
+    Rss,
+    Atom,
+    Json,
+    Podcast,
+}
+
+impl FeedFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "rss",
+            FeedFormat::Atom => "atom",
+            FeedFormat::Json => "json",
+            FeedFormat::Podcast => "podcast",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            FeedFormat::Rss | FeedFormat::Podcast => "application/rss+xml",
+            FeedFormat::Atom => "application/atom+xml",
+            FeedFormat::Json => "application/feed+json",
+        }
+    }
+
+    /// Picks format by `Accept` header value, RSS is used when nothing matches.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(accept) if accept.contains(FeedFormat::Atom.content_type()) => FeedFormat::Atom,
+            Some(accept) if accept.contains(FeedFormat::Json.content_type()) => FeedFormat::Json,
+            _ => FeedFormat::Rss,
+        }
+    }
+}
+
+impl FromStr for FeedFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rss" => Ok(FeedFormat::Rss),
+            "atom" => Ok(FeedFormat::Atom),
+            "json" => Ok(FeedFormat::Json),
+            "podcast" => Ok(FeedFormat::Podcast),
+            _ => anyhow::bail!("unknown feed format: {}", s),
+        }
+    }
+}
+
+pub fn render(
+    format: FeedFormat,
+    settings: &FeedSettings,
+    text_format: TextFormat,
+    channel: &Channel,
+    posts: &[Post],
+    next_url: Option<&str>,
+) -> anyhow::Result<String> {
+    match format {
+        FeedFormat::Rss => {
+            Ok(rss_feed::build(settings, text_format, channel, posts, next_url)?.to_string())
+        }
+        FeedFormat::Atom => {
+            Ok(atom_feed::build(settings, text_format, channel, posts, next_url).to_string())
+        }
+        FeedFormat::Json => Ok(serde_json::to_string(&json_feed::build(
+            settings,
+            text_format,
+            channel,
+            posts,
+            next_url,
+        ))?),
+        FeedFormat::Podcast => {
+            Ok(podcast_feed::build(settings, channel, posts, next_url)?.to_string())
+        }
+    }
+}
+
+/// Url of the feed page with posts published before `before` post.
+pub fn next_page_url(
+    settings: &FeedSettings,
+    channel_name: &str,
+    format: FeedFormat,
+    text_format: Option<TextFormat>,
+    before: TelegramPostId,
+) -> String {
+    let mut url = format!(
+        "{}/channel/{}/{}?before={}",
+        settings.base_url.trim_end_matches('/'),
+        channel_name,
+        format.name(),
+        before
+    );
+    match text_format {
+        None => {}
+        Some(TextFormat::Html) => url.push_str("&text=html"),
+        Some(TextFormat::Markdown) => url.push_str("&text=markdown"),
+        Some(TextFormat::Plain) => url.push_str("&text=plain"),
+    }
+    url
+}
+
+pub(crate) fn file_url(settings: &FeedSettings, remote_id: &str) -> String {
+    format!(
+        "{}/files/{}",
+        settings.base_url.trim_end_matches('/'),
+        remote_id
+    )
+}
+
+fn mime_type(file: &File) -> &str {
+    file.mime_type
+        .as_deref()
+        .unwrap_or("application/octet-stream")
+}
+
+fn to_datetime(timestamp: i32) -> FixedDateTime {
+    FixedOffset::east(0).timestamp(timestamp as i64, 0)
+}
+
+/// Files attached to the post, except thumbnails of other files and link preview photo.
+fn attachments(post: &Post) -> impl Iterator<Item = &File> {
+    let files = post.files();
+    let web_page_photo = post.web_page().and_then(|w| w.photo.as_deref());
+    files.iter().filter(move |f| {
+        web_page_photo != Some(f.remote_id.as_str())
+            && !files
+                .iter()
+                .any(|other| other.thumbnail.as_deref() == Some(f.remote_id.as_str()))
+    })
+}
+
+/// Image to preview the file: the file itself for images or its thumbnail otherwise.
+fn preview<'a>(files: &'a [File], file: &'a File) -> Option<&'a File> {
+    if file.is_image() {
+        return Some(file);
+    }
+    let thumbnail = file.thumbnail.as_deref()?;
+    files.iter().find(|f| f.remote_id == thumbnail)
+}
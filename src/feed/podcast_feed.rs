@@ -0,0 +1,94 @@
+use super::rss_feed::{enclosure, guid, image, next_link, rss_err, ATOM_NAMESPACE};
+use super::{attachments, file_url, preview, to_datetime};
+use crate::models::{Channel, File, Post};
+use crate::settings::FeedSettings;
+use rss::extension::itunes::{ITunesChannelExtensionBuilder, ITunesItemExtensionBuilder};
+use std::collections::HashMap;
+
+/// Builds RSS feed with iTunes tags from posts with audio files only.
+pub fn build(
+    settings: &FeedSettings,
+    channel: &Channel,
+    posts: &[Post],
+    next_url: Option<&str>,
+) -> anyhow::Result<rss::Channel> {
+    let explicit = explicit(settings).to_string();
+    let image_url = channel.photo.as_ref().map(|p| file_url(settings, p));
+
+    let mut items = Vec::new();
+    for p in posts.iter() {
+        let audio = match attachments(p).find(|f| is_audio(f)) {
+            None => continue,
+            Some(audio) => audio,
+        };
+        let guid = guid(settings, p)?;
+        let itunes = ITunesItemExtensionBuilder::default()
+            .author(Some(channel.title.clone()))
+            .duration(audio.duration.map(format_duration))
+            .explicit(Some(explicit.clone()))
+            .image(
+                preview(p.files(), audio)
+                    .map(|f| file_url(settings, &f.remote_id))
+                    .or_else(|| image_url.clone()),
+            )
+            .build()
+            .map_err(rss_err)?;
+        let item = rss::ItemBuilder::default()
+            .title(p.title().clone().or_else(|| audio.file_name.clone()))
+            .link(p.link().to_string())
+            .guid(Some(guid))
+            .pub_date(to_datetime(p.pub_date()).to_rfc2822())
+            .description(p.content().to_string())
+            .enclosure(Some(enclosure(settings, audio)?))
+            .itunes_ext(Some(itunes))
+            .build()
+            .map_err(rss_err)?;
+        items.push(item);
+    }
+
+    let itunes = ITunesChannelExtensionBuilder::default()
+        .author(Some(channel.title.clone()))
+        .explicit(Some(explicit))
+        .image(image_url)
+        .summary(Some(channel.description.clone()).filter(|d| !d.is_empty()))
+        .build()
+        .map_err(rss_err)?;
+    let mut namespaces = HashMap::new();
+    namespaces.insert("atom".to_string(), ATOM_NAMESPACE.to_string());
+    let feed = rss::ChannelBuilder::default()
+        .title(channel.title.clone())
+        .description(channel.description.clone())
+        .link(channel.link())
+        .image(image(settings, channel)?)
+        .itunes_ext(Some(itunes))
+        .namespaces(namespaces)
+        .extensions(next_link(next_url)?)
+        .items(items)
+        .build()
+        .map_err(|e| anyhow::anyhow!("error during building podcast feed: {}", e))?;
+    Ok(feed)
+}
+
+fn is_audio(file: &File) -> bool {
+    file.mime_type
+        .as_deref()
+        .map(|m| m.starts_with("audio/"))
+        .unwrap_or_default()
+}
+
+fn explicit(settings: &FeedSettings) -> &'static str {
+    match settings.podcast_explicit {
+        true => "true",
+        false => "false",
+    }
+}
+
+/// Formats duration as `HH:MM:SS`, as podcast apps expect.
+fn format_duration(seconds: i32) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,195 @@
+use super::{attachments, file_url, mime_type, preview, to_datetime};
+use crate::models::{Channel, File, Post};
+use crate::settings::{FeedSettings, GuidPolicy, TextFormat};
+use rss::extension::{Extension, ExtensionBuilder, ExtensionMap};
+use std::collections::HashMap;
+
+const MEDIA_NAMESPACE: &str = "http://search.yahoo.com/mrss/";
+pub(super) const ATOM_NAMESPACE: &str = "http://www.w3.org/2005/Atom";
+
+pub fn build(
+    settings: &FeedSettings,
+    text_format: TextFormat,
+    channel: &Channel,
+    posts: &[Post],
+    next_url: Option<&str>,
+) -> anyhow::Result<rss::Channel> {
+    let mut items = Vec::with_capacity(posts.len());
+    for p in posts.iter() {
+        let guid = guid(settings, p)?;
+        let enclosure = match attachments(p).next() {
+            None => None,
+            Some(f) => Some(enclosure(settings, f)?),
+        };
+        // `content:encoded` is html, other formats go to the description as text
+        let (description, content) = match text_format {
+            TextFormat::Html => (
+                p.summary().map(str::to_string),
+                Some(p.content().to_string()),
+            ),
+            TextFormat::Markdown | TextFormat::Plain => (Some(p.content().to_string()), None),
+        };
+        let item = rss::ItemBuilder::default()
+            .title(p.title().clone().unwrap_or_default())
+            .link(p.link().to_string())
+            .guid(Some(guid))
+            .pub_date(to_datetime(p.pub_date()).to_rfc2822())
+            .description(description)
+            .content(content)
+            .enclosure(enclosure)
+            .extensions(item_extensions(settings, p)?)
+            .build()
+            .map_err(rss_err)?;
+        items.push(item);
+    }
+    let mut namespaces = HashMap::new();
+    namespaces.insert("media".to_string(), MEDIA_NAMESPACE.to_string());
+    namespaces.insert("atom".to_string(), ATOM_NAMESPACE.to_string());
+    let feed = rss::ChannelBuilder::default()
+        .title(channel.title.clone())
+        .description(channel.description.clone())
+        .link(channel.link())
+        .image(image(settings, channel)?)
+        .namespaces(namespaces)
+        .extensions(next_link(next_url)?)
+        .items(items)
+        .build()
+        .map_err(|e| anyhow::anyhow!("error during building feed: {}", e))?;
+    Ok(feed)
+}
+
+pub(super) fn guid(settings: &FeedSettings, post: &Post) -> anyhow::Result<rss::Guid> {
+    let value = match (settings.guid_policy, post.edit_date()) {
+        (GuidPolicy::Versioned, Some(edit_date)) => {
+            format!("{}:{}", post.telegram_id(), edit_date)
+        }
+        _ => post.telegram_id().to_string(),
+    };
+    rss::GuidBuilder::default()
+        .value(value)
+        .permalink(false)
+        .build()
+        .map_err(rss_err)
+}
+
+/// `atom:link` to the next page of the feed, as RFC 5005 suggests for paged feeds.
+pub(super) fn next_link(next_url: Option<&str>) -> anyhow::Result<ExtensionMap> {
+    let mut extensions = ExtensionMap::new();
+    if let Some(next_url) = next_url {
+        let mut attrs = HashMap::new();
+        attrs.insert("rel".to_string(), "next".to_string());
+        attrs.insert("href".to_string(), next_url.to_string());
+        extensions
+            .entry("atom".to_string())
+            .or_default()
+            .insert("link".to_string(), vec![media_element("atom:link", attrs)?]);
+    }
+    Ok(extensions)
+}
+
+pub(super) fn enclosure(settings: &FeedSettings, file: &File) -> anyhow::Result<rss::Enclosure> {
+    rss::EnclosureBuilder::default()
+        .url(file_url(settings, &file.remote_id))
+        .length(file.size.to_string())
+        .mime_type(mime_type(file).to_string())
+        .build()
+        .map_err(rss_err)
+}
+
+pub(super) fn image(
+    settings: &FeedSettings,
+    channel: &Channel,
+) -> anyhow::Result<Option<rss::Image>> {
+    match &channel.photo {
+        None => Ok(None),
+        Some(photo) => Ok(Some(
+            rss::ImageBuilder::default()
+                .url(file_url(settings, photo))
+                .title(channel.title.clone())
+                .link(channel.link())
+                .build()
+                .map_err(rss_err)?,
+        )),
+    }
+}
+
+fn item_extensions(settings: &FeedSettings, post: &Post) -> anyhow::Result<ExtensionMap> {
+    let mut extensions = media_extensions(settings, post)?;
+    if post.edit_date().is_some() {
+        let updated = ExtensionBuilder::default()
+            .name("atom:updated".to_string())
+            .value(Some(to_datetime(post.updated()).to_rfc3339()))
+            .build()
+            .map_err(rss_err)?;
+        extensions
+            .entry("atom".to_string())
+            .or_default()
+            .insert("updated".to_string(), vec![updated]);
+    }
+    Ok(extensions)
+}
+
+/// Builds `media:content` for every attached file and `media:thumbnail` for its preview.
+fn media_extensions(settings: &FeedSettings, post: &Post) -> anyhow::Result<ExtensionMap> {
+    let mut extensions = ExtensionMap::new();
+    let files = post.files();
+    let mut contents = Vec::with_capacity(files.len());
+    let mut thumbnails = Vec::new();
+    for f in attachments(post) {
+        let mut attrs = HashMap::new();
+        attrs.insert("url".to_string(), file_url(settings, &f.remote_id));
+        attrs.insert("type".to_string(), mime_type(f).to_string());
+        attrs.insert("medium".to_string(), medium(f).to_string());
+        if f.size > 0 {
+            attrs.insert("fileSize".to_string(), f.size.to_string());
+        }
+        if let Some(duration) = f.duration {
+            attrs.insert("duration".to_string(), duration.to_string());
+        }
+        if let (Some(width), Some(height)) = (f.width, f.height) {
+            attrs.insert("width".to_string(), width.to_string());
+            attrs.insert("height".to_string(), height.to_string());
+        }
+        contents.push(media_element("media:content", attrs)?);
+
+        if let Some(preview) = preview(files, f) {
+            let mut attrs = HashMap::new();
+            attrs.insert("url".to_string(), file_url(settings, &preview.remote_id));
+            if let (Some(width), Some(height)) = (preview.width, preview.height) {
+                attrs.insert("width".to_string(), width.to_string());
+                attrs.insert("height".to_string(), height.to_string());
+            }
+            thumbnails.push(media_element("media:thumbnail", attrs)?);
+        }
+    }
+    if contents.is_empty() {
+        return Ok(extensions);
+    }
+    let media = extensions.entry("media".to_string()).or_default();
+    media.insert("content".to_string(), contents);
+    if !thumbnails.is_empty() {
+        media.insert("thumbnail".to_string(), thumbnails);
+    }
+    Ok(extensions)
+}
+
+fn media_element(name: &str, attrs: HashMap<String, String>) -> anyhow::Result<Extension> {
+    ExtensionBuilder::default()
+        .name(name.to_string())
+        .attrs(attrs)
+        .build()
+        .map_err(rss_err)
+}
+
+fn medium(file: &File) -> &'static str {
+    match file.mime_type.as_deref() {
+        Some(m) if m.starts_with("image/") => "image",
+        Some(m) if m.starts_with("video/") => "video",
+        Some(m) if m.starts_with("audio/") => "audio",
+        _ => "document",
+    }
+}
+
+pub(super) fn rss_err<E: std::fmt::Debug>(err: E) -> anyhow::Error {
+    anyhow::anyhow!("error building rss feed: {:?}", err)
+}
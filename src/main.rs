@@ -1,5 +1,6 @@
 mod app;
 mod db;
+mod feed;
 pub mod models;
 mod server;
 mod settings;
@@ -16,6 +17,12 @@ use telegram::TelegramService;
 async fn main() {
     env_logger::init();
     let settings = Settings::new().expect("can't get config");
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        migrate(settings.db.path.as_str())
+            .await
+            .expect("can't migrate db");
+        return;
+    }
     log::info!("initializing database");
     let db = DbService::new(settings.db.path.as_str())
         .await
@@ -25,9 +32,10 @@ async fn main() {
         settings.telegram.api_hash,
         settings.telegram.api_id,
         settings.telegram.phone,
+        settings.telegram.photos,
     );
 
-    let app = App::new(telegram, db);
+    let app = App::new(telegram, db, settings.feed);
     app.start().await.expect("cannot start application");
 
     app.synchronize_channels()
@@ -40,3 +48,18 @@ async fn main() {
     log::info!("starting web server");
     server::run_server(app).await;
 }
+
+/// Applies pending migrations, reporting state of every migration before.
+async fn migrate(db_path: &str) -> anyhow::Result<()> {
+    let db = DbService::connect(db_path).await?;
+    for migration in db.migration_statuses().await?.iter() {
+        let state = match migration.applied {
+            true => "applied",
+            false => "pending",
+        };
+        println!("{} {} {}", migration.version, state, migration.description);
+    }
+    db.migrate().await?;
+    println!("database is up to date");
+    Ok(())
+}
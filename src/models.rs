@@ -1,4 +1,5 @@
-use rust_tdlib::types::{Chat, File as TgFile};
+use rust_tdlib::types::{Chat, File as TgFile, FormattedText};
+use serde::{Deserialize, Serialize};
 
 pub type TelegramPostId = i64;
 pub type TelegramChatId = i64;
@@ -11,7 +12,17 @@ pub struct Post {
     pub pub_date: i32,
     pub content: String,
     pub chat_id: TelegramChatId,
-    pub files: Vec<i32>,
+    pub files: Vec<File>,
+    pub edit_date: Option<i32>,
+    pub deleted_at: Option<i32>,
+    pub media_album_id: Option<i64>,
+    pub forwarded_from: Option<ForwardedFrom>,
+    pub reply_to: Option<TelegramPostId>,
+    // source of the content, if it was made of text with entities
+    pub formatted_text: Option<FormattedText>,
+    // plain text version of the content, filled before rendering feed
+    pub summary: Option<String>,
+    pub web_page: Option<WebPage>,
 }
 
 impl Post {
@@ -33,6 +44,69 @@ impl Post {
     pub fn chat_id(&self) -> TelegramChatId {
         self.chat_id
     }
+    pub fn files(&self) -> &[File] {
+        &self.files
+    }
+    pub fn edit_date(&self) -> Option<i32> {
+        self.edit_date
+    }
+    pub fn media_album_id(&self) -> Option<i64> {
+        self.media_album_id
+    }
+    pub fn forwarded_from(&self) -> Option<&ForwardedFrom> {
+        self.forwarded_from.as_ref()
+    }
+    pub fn reply_to(&self) -> Option<TelegramPostId> {
+        self.reply_to
+    }
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+    pub fn web_page(&self) -> Option<&WebPage> {
+        self.web_page.as_ref()
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+    /// Date of the last change, publication date for never edited posts.
+    pub fn updated(&self) -> i32 {
+        self.edit_date.unwrap_or(self.pub_date)
+    }
+}
+
+/// Origin of forwarded post. Chat and user ids are resolved into name and link
+/// by telegram service, only name and link are stored.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardedFrom {
+    pub name: Option<String>,
+    pub link: Option<String>,
+    pub chat_id: Option<TelegramChatId>,
+    pub user_id: Option<i32>,
+    pub message_id: Option<TelegramPostId>,
+}
+
+/// Link preview of text post.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebPage {
+    pub url: String,
+    pub display_url: String,
+    pub site_name: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    // remote_id of the preview photo, the file itself is attached to the post
+    pub photo: Option<String>,
+}
+
+/// Changes of already published post, tdlib sends new content and edit date separately.
+#[derive(Debug)]
+pub struct EditedPost {
+    pub telegram_id: TelegramPostId,
+    pub chat_id: TelegramChatId,
+    pub content: Option<String>,
+    pub formatted_text: Option<FormattedText>,
+    pub web_page: Option<WebPage>,
+    pub files: Vec<File>,
+    pub edit_date: Option<i32>,
 }
 
 #[derive(Debug)]
@@ -40,6 +114,9 @@ pub struct NewChannel {
     pub title: String,
     pub telegram_id: TelegramChatId,
     pub username: String,
+    pub description: String,
+    pub member_count: i32,
+    pub photo: Option<File>,
 }
 
 #[derive(Debug, sqlx::FromRow)]
@@ -48,15 +125,76 @@ pub struct Channel {
     pub title: String,
     pub username: String,
     pub telegram_id: TelegramChatId,
+    pub description: String,
+    pub member_count: i64,
+    // remote_id of profile photo
+    pub photo: Option<String>,
 }
 
-#[derive(Debug, PartialEq)]
+impl Channel {
+    pub fn link(&self) -> String {
+        format!("https://t.me/{}", self.username)
+    }
+}
+
+/// Builds public `t.me` link to the channel post.
+pub fn post_link(username: &str, telegram_id: TelegramPostId) -> String {
+    // tdlib message ids are server message ids shifted by 20 bits
+    format!("https://t.me/{}/{}", username, telegram_id >> 20)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct File {
     pub local_path: Option<String>,
     // id to download
     pub remote_file: i32,
     // id to make requests
     pub remote_id: String,
+    pub mime_type: Option<String>,
+    pub size: i64,
+    pub file_name: Option<String>,
+    // in seconds, for audio and video files
+    pub duration: Option<i32>,
+    // remote_id of thumbnail file attached to the same post
+    pub thumbnail: Option<String>,
+    // in pixels, for photos
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+impl File {
+    pub fn with_mime_type(mut self, mime_type: &str) -> Self {
+        self.mime_type = Some(mime_type.to_string()).filter(|m| !m.is_empty());
+        self
+    }
+
+    pub fn with_file_name(mut self, file_name: &str) -> Self {
+        self.file_name = Some(file_name.to_string()).filter(|n| !n.is_empty());
+        self
+    }
+
+    pub fn with_duration(mut self, duration: i32) -> Self {
+        self.duration = Some(duration).filter(|d| *d > 0);
+        self
+    }
+
+    pub fn with_dimensions(mut self, width: i32, height: i32) -> Self {
+        self.width = Some(width).filter(|w| *w > 0);
+        self.height = Some(height).filter(|h| *h > 0);
+        self
+    }
+
+    pub fn with_thumbnail(mut self, thumbnail: &File) -> Self {
+        self.thumbnail = Some(thumbnail.remote_id.clone());
+        self
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.mime_type
+            .as_deref()
+            .map(|m| m.starts_with("image/"))
+            .unwrap_or_default()
+    }
 }
 
 impl From<&TgFile> for File {
@@ -67,6 +205,13 @@ impl From<&TgFile> for File {
                 .map(|s| s.to_string()),
             remote_file: file.id(),
             remote_id: file.remote().unique_id().clone(),
+            mime_type: None,
+            size: file.size() as i64,
+            file_name: None,
+            duration: None,
+            thumbnail: None,
+            width: None,
+            height: None,
         }
     }
 }
@@ -1,24 +1,63 @@
 use crate::app::App;
+use warp::Filter;
 
 pub async fn run_server(app: App) -> anyhow::Result<()> {
-    let routes = filters::channel(app);
+    let routes = filters::channel_with_format(app.clone())
+        .or(filters::channel(app.clone()))
+        .or(filters::file(app));
     warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
     Ok(())
 }
 mod filters {
     use super::handlers;
     use crate::app::App;
+    use crate::feed::FeedFormat;
+    use crate::models::TelegramPostId;
+    use crate::settings::TextFormat;
+    use serde::Deserialize;
     use warp::Filter;
 
+    /// Query parameters accepted by feed routes.
+    #[derive(Debug, Deserialize)]
+    pub struct FeedQuery {
+        pub text: Option<TextFormat>,
+        /// Telegram id of the last post of the previous page.
+        pub before: Option<TelegramPostId>,
+    }
+
     pub fn channel(
         app: App,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path!("channel" / String)
             .and(warp::get())
+            .and(warp::header::optional::<String>("accept"))
+            .map(|channel_name, accept: Option<String>| {
+                (channel_name, FeedFormat::from_accept(accept.as_deref()))
+            })
+            .untuple_one()
+            .and(warp::query::<FeedQuery>())
+            .and(with_app(app))
+            .and_then(handlers::channel)
+    }
+
+    pub fn channel_with_format(
+        app: App,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("channel" / String / FeedFormat)
+            .and(warp::get())
+            .and(warp::query::<FeedQuery>())
             .and(with_app(app))
             .and_then(handlers::channel)
     }
 
+    pub fn file(app: App) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("files" / String)
+            .and(warp::get())
+            .and(warp::header::optional::<String>("range"))
+            .and(with_app(app))
+            .and_then(handlers::file)
+    }
+
     fn with_app(
         db: App,
     ) -> impl Filter<Extract = (App,), Error = std::convert::Infallible> + Clone {
@@ -27,29 +66,77 @@ mod filters {
 }
 
 mod handlers {
+    use super::filters::FeedQuery;
     use crate::app::App;
-    use warp::http::header::CONTENT_TYPE;
-    use warp::http::StatusCode;
+    use crate::feed::{self, FeedFormat};
+    use futures::Stream;
+    use std::io::{self, SeekFrom};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+    use warp::http::header::{
+        ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    };
+    use warp::http::{HeaderValue, StatusCode};
+    use warp::hyper::Body;
     use warp::Reply;
 
+    const CHUNK_SIZE: usize = 64 * 1024;
+    // files are addressed by telegram unique id, so their content never changes
+    const FILE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
     pub async fn channel(
         channel_name: String,
+        format: FeedFormat,
+        query: FeedQuery,
         app: App,
     ) -> Result<impl warp::Reply, warp::Rejection> {
         let response: warp::reply::Response;
-        match app.get_posts_or_search(channel_name.as_str()).await {
+        let text_format = app.text_format(channel_name.as_str(), query.text);
+        // podcast pages are counted in episodes, not in posts
+        let audio_only = format == FeedFormat::Podcast;
+        match app
+            .get_posts_or_search(
+                channel_name.as_str(),
+                Some(text_format),
+                audio_only,
+                query.before,
+            )
+            .await
+        {
             Ok(feed) => match feed {
                 None => {
                     response = warp::reply::with_status("".to_string(), StatusCode::NOT_FOUND)
                         .into_response();
                 }
-                Some(feed) => {
-                    response = warp::reply::with_header(
-                        feed.to_string(),
-                        CONTENT_TYPE,
-                        "application/rss+xml",
-                    )
-                    .into_response()
+                Some((channel, posts)) => {
+                    let settings = app.feed_settings();
+                    // a full page means there may be older posts
+                    let next_url = posts
+                        .last()
+                        .filter(|_| posts.len() >= settings.page_size as usize)
+                        .map(|last| {
+                            feed::next_page_url(
+                                settings,
+                                channel_name.as_str(),
+                                format,
+                                query.text,
+                                last.telegram_id(),
+                            )
+                        });
+                    let rendered = feed::render(
+                        format,
+                        settings,
+                        text_format,
+                        &channel,
+                        &posts,
+                        next_url.as_deref(),
+                    );
+                    response = match rendered {
+                        Ok(feed) => {
+                            warp::reply::with_header(feed, CONTENT_TYPE, format.content_type())
+                                .into_response()
+                        }
+                        Err(err) => internal_error(err),
+                    }
                 }
             },
             Err(err) => {
@@ -60,4 +147,120 @@ mod handlers {
         }
         Ok(response)
     }
+
+    pub async fn file(
+        remote_id: String,
+        range: Option<String>,
+        app: App,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let file = match app.get_file(remote_id.as_str()).await {
+            Ok(Some(file)) => file,
+            Ok(None) => return Ok(StatusCode::NOT_FOUND.into_response()),
+            Err(err) => return Ok(internal_error(err)),
+        };
+        let local_path = match &file.local_path {
+            None => return Ok(StatusCode::ACCEPTED.into_response()),
+            Some(path) => path,
+        };
+        let mut local_file = match tokio::fs::File::open(local_path).await {
+            Ok(f) => f,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                log::info!("file {} removed from disk, downloading again", local_path);
+                if let Err(err) = app.download_file(file.remote_file).await {
+                    return Ok(internal_error(err));
+                }
+                return Ok(StatusCode::ACCEPTED.into_response());
+            }
+            Err(err) => return Ok(internal_error(err)),
+        };
+        let len = match local_file.metadata().await {
+            Ok(metadata) => metadata.len(),
+            Err(err) => return Ok(internal_error(err)),
+        };
+
+        let (status, start, end) = match parse_range(range.as_deref(), len) {
+            Err(()) => {
+                return Ok(warp::reply::with_header(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    CONTENT_RANGE,
+                    format!("bytes */{}", len),
+                )
+                .into_response())
+            }
+            Ok(None) => (StatusCode::OK, 0, len),
+            Ok(Some((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+        };
+        if start > 0 {
+            if let Err(err) = local_file.seek(SeekFrom::Start(start)).await {
+                return Ok(internal_error(err));
+            }
+        }
+
+        let mut response =
+            warp::reply::Response::new(Body::wrap_stream(read_chunks(local_file.take(end - start))));
+        *response.status_mut() = status;
+        let headers = response.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            file.mime_type
+                .as_deref()
+                .and_then(|m| HeaderValue::from_str(m).ok())
+                .unwrap_or_else(|| HeaderValue::from_static("application/octet-stream")),
+        );
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(end - start));
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static(FILE_CACHE_CONTROL));
+        if status == StatusCode::PARTIAL_CONTENT {
+            let content_range = format!("bytes {}-{}/{}", start, end - 1, len);
+            if let Ok(value) = HeaderValue::from_str(content_range.as_str()) {
+                headers.insert(CONTENT_RANGE, value);
+            }
+        }
+        Ok(response)
+    }
+
+    fn internal_error<E: std::fmt::Display>(err: E) -> warp::reply::Response {
+        warp::reply::with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
+    }
+
+    /// Parses single `bytes` range into `[start, end)` bounds.
+    /// Returns `Ok(None)` when whole file should be sent and `Err` when range can't be satisfied.
+    fn parse_range(header: Option<&str>, len: u64) -> Result<Option<(u64, u64)>, ()> {
+        let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+            // multiple ranges are not supported, so such requests get whole file
+            Some(spec) if !spec.contains(',') => spec.trim(),
+            _ => return Ok(None),
+        };
+        let (start, end) = match spec.split_once('-') {
+            None => return Ok(None),
+            Some(bounds) => bounds,
+        };
+        let bounds = match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.saturating_add(1).min(len)),
+            (Ok(start), Err(_)) if end.is_empty() => (start, len),
+            (Err(_), Ok(suffix)) if start.is_empty() => (len.saturating_sub(suffix), len),
+            _ => return Ok(None),
+        };
+        if bounds.0 >= len || bounds.0 >= bounds.1 {
+            return Err(());
+        }
+        Ok(Some(bounds))
+    }
+
+    fn read_chunks<R: AsyncRead + Unpin + Send + 'static>(
+        reader: R,
+    ) -> impl Stream<Item = io::Result<Vec<u8>>> + Send + 'static {
+        futures::stream::unfold(Some(reader), |reader| async move {
+            let mut reader = reader?;
+            let mut buf = vec![0; CHUNK_SIZE];
+            match reader.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(buf), Some(reader)))
+                }
+                Err(err) => Some((Err(err), None)),
+            }
+        })
+    }
 }
@@ -1,5 +1,6 @@
 use config::{Config, File};
 use serde::Deserialize;
+use std::collections::HashMap;
 
 #[derive(Debug, Deserialize)]
 pub struct DbSettings {
@@ -11,12 +12,131 @@ pub struct TelegramSettings {
     pub api_hash: String,
     pub api_id: i32,
     pub phone: String,
+    #[serde(default)]
+    pub photos: PhotoSettings,
+}
+
+/// Which of the sizes telegram provides for every photo are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PhotoSizePolicy {
+    /// The largest size only.
+    Largest,
+    /// The largest size within `max_side` and `max_bytes` limits.
+    Limited,
+    /// The largest size along with the smallest one as its thumbnail.
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct PhotoSettings {
+    pub size_policy: PhotoSizePolicy,
+    /// Maximum width and height in pixels for `limited` policy.
+    pub max_side: Option<i32>,
+    /// Maximum file size in bytes for `limited` policy.
+    pub max_bytes: Option<i64>,
+}
+
+impl Default for PhotoSettings {
+    fn default() -> Self {
+        Self {
+            size_policy: PhotoSizePolicy::Largest,
+            max_side: Some(1280),
+            max_bytes: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuidPolicy {
+    /// Item guid never changes, readers update edited posts in place.
+    Stable,
+    /// Item guid includes edit date, so readers show edited posts as new items.
+    Versioned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletedPostsPolicy {
+    /// Deleted posts disappear from feeds.
+    Hide,
+    /// Deleted posts stay in feeds with a note about deletion.
+    Mark,
+    /// Deletions are ignored, posts are kept as they were.
+    Keep,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextFormat {
+    Html,
+    Markdown,
+    Plain,
+}
+
+/// How item titles are made of posts without one.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TitlePolicy {
+    /// Items have no titles.
+    None,
+    /// First non-empty line of the post.
+    Line,
+    /// First sentence of the first line.
+    Sentence,
+    /// Beginning of the post, `title_length` characters at most.
+    Chars,
+    /// First bold text, or first line if there is none.
+    Bold,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FeedSettings {
+    /// Public address of tgfeed, used to build links to served files.
+    pub base_url: String,
+    /// Value of `itunes:explicit` in podcast feeds.
+    pub podcast_explicit: bool,
+    pub guid_policy: GuidPolicy,
+    pub deleted_posts: DeletedPostsPolicy,
+    /// Format of item content, unless requested explicitly.
+    pub text_format: TextFormat,
+    /// Per channel overrides of `text_format`, keyed by channel username.
+    pub channel_text_formats: HashMap<String, TextFormat>,
+    pub title_policy: TitlePolicy,
+    /// Derived titles longer than this are cut with an ellipsis.
+    pub title_length: usize,
+    /// Posts consisting of a link only are titled by the link preview.
+    pub web_page_titles: bool,
+    /// Number of posts in a feed, older posts are available by `rel="next"` links.
+    pub page_size: i32,
+}
+
+impl Default for FeedSettings {
+    fn default() -> Self {
+        Self {
+            base_url: "http://127.0.0.1:3030".to_string(),
+            podcast_explicit: false,
+            guid_policy: GuidPolicy::Stable,
+            deleted_posts: DeletedPostsPolicy::Hide,
+            text_format: TextFormat::Html,
+            channel_text_formats: HashMap::new(),
+            title_policy: TitlePolicy::Line,
+            title_length: 80,
+            web_page_titles: true,
+            page_size: 25,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub telegram: TelegramSettings,
     pub db: DbSettings,
+    #[serde(default)]
+    pub feed: FeedSettings,
 }
 
 impl Settings {
@@ -1,4 +1,8 @@
-use crate::models::{Channel, File, NewChannel, Post};
+use crate::models::{
+    post_link, Channel, EditedPost, File, ForwardedFrom, NewChannel, Post, TelegramChatId,
+    TelegramPostId,
+};
+use crate::settings::PhotoSettings;
 use anyhow::Result;
 use rust_tdlib::client::tdlib_client::TdJson;
 use rust_tdlib::client::{
@@ -10,7 +14,8 @@ use rust_tdlib::types::{
     AuthorizationStateWaitOtherDeviceConfirmation, AuthorizationStateWaitPassword,
     AuthorizationStateWaitPhoneNumber, AuthorizationStateWaitRegistration, Chat, ChatType,
     DownloadFile, FileType, FormattedText, GetChat, GetChatHistory, GetChats, GetSupergroup,
-    MessageContent, SearchPublicChat, TdlibParameters, TextEntity, TextEntityType, Update,
+    GetSupergroupFullInfo, GetUser, MessageContent, SearchPublicChat, SupergroupFullInfo,
+    TdlibParameters, TextEntity, TextEntityType, Update,
 };
 use std::io;
 use std::ops::Deref;
@@ -24,11 +29,17 @@ use warp::any;
 
 mod parsers;
 
+pub use parsers::{
+    first_bold, render_formatted_text, renderer, strip_html, Entity, PlainRenderer, Renderer,
+};
+
 const SEND_UPDATE_TIMEOUT: Duration = Duration::from_secs(15);
 
 #[derive(Debug)]
 pub enum NewUpdate {
-    Post(Post),
+    Post(Box<Post>),
+    EditedPost(Box<EditedPost>),
+    DeletedPosts(TelegramChatId, Vec<TelegramPostId>),
     Channel(NewChannel),
     File(File),
 }
@@ -38,6 +49,7 @@ pub struct TelegramService {
     api_hash: String,
     app_id: i32,
     phone_number: String,
+    photos: PhotoSettings,
     inner: Arc<RwLock<Option<Inner>>>,
 }
 
@@ -62,11 +74,12 @@ impl Inner {
 }
 
 impl TelegramService {
-    pub fn new(api_hash: String, app_id: i32, phone_number: String) -> Self {
+    pub fn new(api_hash: String, app_id: i32, phone_number: String, photos: PhotoSettings) -> Self {
         Self {
             api_hash,
             app_id,
             phone_number,
+            photos,
             inner: Arc::new(RwLock::new(None)),
         }
     }
@@ -92,7 +105,7 @@ impl TelegramService {
             .with_updates_sender(sender)
             .build()?;
 
-        let receiver = init_updates_reader(receiver);
+        let receiver = init_updates_reader(receiver, self.photos);
 
         let mut worker = Worker::builder()
             .with_auth_state_handler(AuthHandler::new("", self.phone_number.as_str()))
@@ -148,14 +161,13 @@ impl TelegramService {
                 let mut result = Vec::with_capacity(history.messages().len());
                 for msg in history.messages().into_iter() {
                     if let Some(msg) = msg {
-                        let (content, file) = match parsers::parse_message_content(msg.content()) {
-                            None => (None, None),
-                            Some((content, file)) => (content, file),
-                        };
-
-                        let mut file_ids = Vec::with_capacity(1);
-                        if let Some(file) = file {
-                            file_ids.push(file.remote_file);
+                        let (content, files) =
+                            match parsers::parse_message_content(msg.content(), &self.photos) {
+                                None => (None, Vec::new()),
+                                Some((content, files)) => (content, files),
+                            };
+
+                        for file in files.iter() {
                             if let Err(err) = self.download_file(file.remote_file).await {
                                 log::error!("cannot download file: {}", err);
                             }
@@ -167,15 +179,79 @@ impl TelegramService {
                             pub_date: msg.date(),
                             content: content.unwrap_or_default(),
                             chat_id,
-                            files: file_ids,
+                            files,
+                            edit_date: Some(msg.edit_date()).filter(|d| *d > 0),
+                            deleted_at: None,
+                            media_album_id: Some(msg.media_album_id()).filter(|id| *id != 0),
+                            forwarded_from: msg
+                                .forward_info()
+                                .as_ref()
+                                .map(parsers::parse_forward_info),
+                            reply_to: Some(msg.reply_to_message_id()).filter(|id| *id != 0),
+                            formatted_text: parsers::message_formatted_text(msg.content()).cloned(),
+                            summary: None,
+                            web_page: parsers::message_web_page(msg.content(), &self.photos),
                         })
                     }
                 }
+                for post in result.iter_mut() {
+                    if let Some(forwarded_from) = post.forwarded_from.as_mut() {
+                        if let Err(err) = self.resolve_forward(forwarded_from).await {
+                            log::error!("cannot resolve forward origin: {}", err);
+                        }
+                    }
+                }
                 Ok(result)
             }
         }
     }
 
+    /// Fills name and link of forwarded post origin.
+    pub async fn resolve_forward(&self, forwarded: &mut ForwardedFrom) -> anyhow::Result<()> {
+        let mb_inner = self.inner.read().await;
+        let inner = match mb_inner.as_ref() {
+            None => {
+                anyhow::bail!("service not started yet")
+            }
+            Some(inner) => inner,
+        };
+        if let Some(chat_id) = forwarded.chat_id {
+            let chat = inner
+                .client
+                .get_chat(GetChat::builder().chat_id(chat_id).build())
+                .await?;
+            forwarded.name = Some(chat.title().clone());
+            if let ChatType::Supergroup(type_sg) = chat.type_() {
+                let sg = inner
+                    .client
+                    .get_supergroup(
+                        GetSupergroup::builder()
+                            .supergroup_id(type_sg.supergroup_id())
+                            .build(),
+                    )
+                    .await?;
+                if !sg.username().is_empty() {
+                    forwarded.link = Some(match forwarded.message_id {
+                        Some(message_id) => post_link(sg.username(), message_id),
+                        None => format!("https://t.me/{}", sg.username()),
+                    });
+                }
+            }
+        }
+        if let Some(user_id) = forwarded.user_id {
+            let user = inner
+                .client
+                .get_user(GetUser::builder().user_id(user_id).build())
+                .await?;
+            let name = format!("{} {}", user.first_name(), user.last_name());
+            forwarded.name = Some(name.trim().to_string());
+            if !user.username().is_empty() {
+                forwarded.link = Some(format!("https://t.me/{}", user.username()));
+            }
+        }
+        Ok(())
+    }
+
     pub async fn download_file(&self, file_id: i32) -> anyhow::Result<()> {
         let mb_inner = self.inner.read().await;
         match mb_inner.as_ref() {
@@ -211,24 +287,8 @@ impl TelegramService {
                     .await?;
                 let mut result = Vec::with_capacity(chats.chat_ids().len());
                 for chat_id in chats.chat_ids().into_iter() {
-                    let chat = inner
-                        .client
-                        .get_chat(GetChat::builder().chat_id(*chat_id).build())
-                        .await?;
-
-                    if let ChatType::Supergroup(type_sg) = chat.type_() {
-                        if type_sg.is_channel() {
-                            let sg = inner
-                                .client
-                                .get_supergroup(
-                                    GetSupergroup::builder()
-                                        .supergroup_id(type_sg.supergroup_id())
-                                        .build(),
-                                )
-                                .await?;
-
-                            result.push(new_channel(chat, sg.username()))
-                        }
+                    if let Some(channel) = get_channel(&inner.client, *chat_id).await? {
+                        result.push(channel)
                     }
                 }
                 Ok(result)
@@ -236,6 +296,17 @@ impl TelegramService {
         }
     }
 
+    /// Channel by chat id, none if the chat is not a channel.
+    pub async fn get_channel(&self, chat_id: TelegramChatId) -> anyhow::Result<Option<NewChannel>> {
+        let mb_inner = self.inner.read().await;
+        match mb_inner.as_ref() {
+            None => {
+                anyhow::bail!("service not started yet")
+            }
+            Some(inner) => get_channel(&inner.client, chat_id).await,
+        }
+    }
+
     pub async fn search_channel(&self, channel_name: &str) -> anyhow::Result<Option<NewChannel>> {
         let mb_inner = self.inner.read().await;
         match mb_inner.as_ref() {
@@ -247,17 +318,29 @@ impl TelegramService {
                     .client
                     .search_public_chat(SearchPublicChat::builder().username(channel_name).build())
                     .await?;
-                if !is_channel(&chat) {
-                    return Ok(None);
-                }
+                let supergroup_id = match chat.type_() {
+                    ChatType::Supergroup(sg) if sg.is_channel() => sg.supergroup_id(),
+                    _ => return Ok(None),
+                };
+                let full_info = inner
+                    .client
+                    .get_supergroup_full_info(
+                        GetSupergroupFullInfo::builder()
+                            .supergroup_id(supergroup_id)
+                            .build(),
+                    )
+                    .await?;
 
-                Ok(Some(new_channel(chat, channel_name)))
+                Ok(Some(new_channel(chat, channel_name, &full_info)))
             }
         }
     }
 }
 
-fn init_updates_reader(mut receiver: Receiver<Box<Update>>) -> Receiver<NewUpdate> {
+fn init_updates_reader(
+    mut receiver: Receiver<Box<Update>>,
+    photos: PhotoSettings,
+) -> Receiver<NewUpdate> {
     let (sx, rx) = mpsc::channel(2000);
 
     tokio::spawn(async move {
@@ -271,37 +354,112 @@ fn init_updates_reader(mut receiver: Receiver<Box<Update>>) -> Receiver<NewUpdat
                         local_path: Some(file.file().local().path().clone()),
                         remote_file: file.file().id(),
                         remote_id: file.file().remote().unique_id().clone(),
+                        mime_type: None,
+                        size: file.file().size() as i64,
+                        file_name: None,
+                        duration: None,
+                        thumbnail: None,
+                        width: None,
+                        height: None,
                     })),
                 },
-                Update::MessageContent(content) => None,
+                Update::MessageContent(content) => {
+                    match parsers::parse_message_content(content.new_content(), &photos) {
+                        None => None,
+                        Some((text, files)) => {
+                            for file in files.iter() {
+                                if let Err(err) = sx
+                                    .send_timeout(NewUpdate::File(file.clone()), SEND_UPDATE_TIMEOUT)
+                                    .await
+                                {
+                                    log::error!("cannot send new file update");
+                                }
+                            }
+                            Some(NewUpdate::EditedPost(Box::new(EditedPost {
+                                telegram_id: content.message_id(),
+                                chat_id: content.chat_id(),
+                                content: Some(text.unwrap_or_default()),
+                                formatted_text: parsers::message_formatted_text(
+                                    content.new_content(),
+                                )
+                                .cloned(),
+                                web_page: parsers::message_web_page(content.new_content(), &photos),
+                                files,
+                                edit_date: None,
+                            })))
+                        }
+                    }
+                }
+                // messages removed from local cache only are still present in the channel
+                Update::DeleteMessages(deleted) => match deleted.is_permanent() && !deleted.from_cache() {
+                    false => None,
+                    true => Some(NewUpdate::DeletedPosts(
+                        deleted.chat_id(),
+                        deleted.message_ids().clone(),
+                    )),
+                },
+                Update::MessageEdited(edited) => {
+                    Some(NewUpdate::EditedPost(Box::new(EditedPost {
+                        telegram_id: edited.message_id(),
+                        chat_id: edited.chat_id(),
+                        content: None,
+                        formatted_text: None,
+                        web_page: None,
+                        files: Vec::new(),
+                        edit_date: Some(edited.edit_date()),
+                    })))
+                }
                 Update::NewChat(new_chat) => None,
                 Update::NewMessage(new_message) => match new_message.message().is_channel_post() {
                     false => None,
                     true => {
-                        let parsed =
-                            parsers::parse_message_content(new_message.message().content());
+                        let parsed = parsers::parse_message_content(
+                            new_message.message().content(),
+                            &photos,
+                        );
                         match parsed {
                             None => None,
-                            Some((content, file)) => {
-                                let mut file_ids = Vec::with_capacity(1);
-                                if let Some(file) = file {
-                                    file_ids.push(file.remote_file);
+                            Some((content, files)) => {
+                                for file in files.iter() {
                                     if let Err(err) = sx
-                                        .send_timeout(NewUpdate::File(file), SEND_UPDATE_TIMEOUT)
+                                        .send_timeout(
+                                            NewUpdate::File(file.clone()),
+                                            SEND_UPDATE_TIMEOUT,
+                                        )
                                         .await
                                     {
                                         log::error!("cannot send new file update");
                                     }
                                 }
-                                Some(NewUpdate::Post(Post {
+                                Some(NewUpdate::Post(Box::new(Post {
                                     title: None,
                                     link: "".to_string(),
                                     telegram_id: new_message.message().id(),
                                     pub_date: new_message.message().date(),
                                     content: content.unwrap_or_default(),
                                     chat_id: new_message.message().chat_id(),
-                                    files: file_ids,
-                                }))
+                                    files,
+                                    edit_date: None,
+                                    deleted_at: None,
+                                    media_album_id: Some(new_message.message().media_album_id())
+                                        .filter(|id| *id != 0),
+                                    forwarded_from: new_message
+                                        .message()
+                                        .forward_info()
+                                        .as_ref()
+                                        .map(parsers::parse_forward_info),
+                                    reply_to: Some(new_message.message().reply_to_message_id())
+                                        .filter(|id| *id != 0),
+                                    formatted_text: parsers::message_formatted_text(
+                                        new_message.message().content(),
+                                    )
+                                    .cloned(),
+                                    summary: None,
+                                    web_page: parsers::message_web_page(
+                                        new_message.message().content(),
+                                        &photos,
+                                    ),
+                                })))
                             }
                         }
                     }
@@ -377,20 +535,44 @@ impl AuthStateHandler for AuthHandler {
     }
 }
 
-fn new_channel(chat: Chat, channel_name: &str) -> NewChannel {
+async fn get_channel(
+    client: &Client<TdJson>,
+    chat_id: TelegramChatId,
+) -> anyhow::Result<Option<NewChannel>> {
+    let chat = client
+        .get_chat(GetChat::builder().chat_id(chat_id).build())
+        .await?;
+    let supergroup_id = match chat.type_() {
+        ChatType::Supergroup(sg) if sg.is_channel() => sg.supergroup_id(),
+        _ => return Ok(None),
+    };
+    let sg = client
+        .get_supergroup(
+            GetSupergroup::builder()
+                .supergroup_id(supergroup_id)
+                .build(),
+        )
+        .await?;
+    let full_info = client
+        .get_supergroup_full_info(
+            GetSupergroupFullInfo::builder()
+                .supergroup_id(supergroup_id)
+                .build(),
+        )
+        .await?;
+    Ok(Some(new_channel(chat, sg.username(), &full_info)))
+}
+
+fn new_channel(chat: Chat, channel_name: &str, full_info: &SupergroupFullInfo) -> NewChannel {
     NewChannel {
         title: chat.title().clone(),
         telegram_id: chat.id(),
         username: channel_name.to_string(),
-    }
-}
-
-fn is_channel(chat: &Chat) -> bool {
-    match chat.type_() {
-        ChatType::_Default => false,
-        ChatType::BasicGroup(g) => false,
-        ChatType::Private(_) => false,
-        ChatType::Secret(_) => false,
-        ChatType::Supergroup(sg) => sg.is_channel(),
+        description: full_info.description().clone(),
+        member_count: full_info.member_count(),
+        photo: chat
+            .photo()
+            .as_ref()
+            .map(|photo| File::from(photo.big()).with_mime_type("image/jpeg")),
     }
 }
@@ -1,49 +1,124 @@
-use crate::models::File;
-use rust_tdlib::types::{FileType, FormattedText, MessageContent, TextEntity, TextEntityType};
+use crate::models::{File, ForwardedFrom, WebPage};
+use crate::settings::{PhotoSettings, PhotoSizePolicy, TextFormat};
+use rust_tdlib::types::{
+    Contact, FileType, FormattedText, Location, MessageContent, MessageForwardInfo,
+    MessageForwardOrigin, Photo, PhotoSize, Poll, TextEntityType, Thumbnail, ThumbnailFormat,
+    Venue,
+};
 
-pub fn parse_message_content(message: &MessageContent) -> Option<(Option<String>, Option<File>)> {
+pub fn parse_message_content(
+    message: &MessageContent,
+    photos: &PhotoSettings,
+) -> Option<(Option<String>, Vec<File>)> {
     match message {
-        MessageContent::MessageText(text) => Some((Some(parse_formatted_text(text.text())), None)),
-        MessageContent::MessageAnimation(message_animation) => Some((
-            Some(parse_formatted_text(message_animation.caption())),
-            Some(message_animation.animation().animation().into()),
-        )),
-        MessageContent::MessageAudio(message_audio) => None,
-        MessageContent::MessageDocument(message_document) => None,
-        MessageContent::MessagePhoto(photo) => {
-            // TODO: choose particular file size
-            let file = match photo.photo().sizes().first() {
-                None => None,
-                Some(photo) => Some(photo.photo().into()),
+        MessageContent::MessageText(text) => {
+            let files = match text.web_page().as_ref().and_then(|w| w.photo().as_ref()) {
+                None => Vec::new(),
+                Some(photo) => photo_files(photo, photos),
             };
-            Some((Some(parse_formatted_text(photo.caption())), file))
+            Some((Some(parse_formatted_text(text.text())), files))
+        }
+        MessageContent::MessageAnimation(message_animation) => {
+            let animation = message_animation.animation();
+            let file = File::from(animation.animation())
+                .with_mime_type(animation.mime_type())
+                .with_file_name(animation.file_name())
+                .with_duration(animation.duration());
+            Some((
+                Some(parse_formatted_text(message_animation.caption())),
+                with_thumbnail(file, animation.thumbnail()),
+            ))
+        }
+        MessageContent::MessageAudio(message_audio) => {
+            let audio = message_audio.audio();
+            let file = File::from(audio.audio())
+                .with_mime_type(audio.mime_type())
+                .with_file_name(audio.file_name())
+                .with_duration(audio.duration());
+            Some((
+                Some(parse_formatted_text(message_audio.caption())),
+                with_thumbnail(file, audio.album_cover_thumbnail()),
+            ))
+        }
+        MessageContent::MessageDocument(message_document) => {
+            let document = message_document.document();
+            let file = File::from(document.document())
+                .with_mime_type(document.mime_type())
+                .with_file_name(document.file_name());
+            Some((
+                Some(parse_formatted_text(message_document.caption())),
+                with_thumbnail(file, document.thumbnail()),
+            ))
+        }
+        MessageContent::MessagePhoto(photo) => {
+            let files = photo_files(photo.photo(), photos);
+            Some((Some(parse_formatted_text(photo.caption())), files))
+        }
+        MessageContent::MessageVideo(message_video) => {
+            let video = message_video.video();
+            let file = File::from(video.video())
+                .with_mime_type(video.mime_type())
+                .with_file_name(video.file_name())
+                .with_duration(video.duration());
+            Some((
+                Some(parse_formatted_text(message_video.caption())),
+                with_thumbnail(file, video.thumbnail()),
+            ))
         }
-        MessageContent::MessageVideo(message_video) => None,
 
         MessageContent::MessageChatChangePhoto(_) => None,
 
-        MessageContent::MessagePoll(_) => None,
+        MessageContent::MessagePoll(message_poll) => {
+            Some((Some(render_poll(message_poll.poll())), Vec::new()))
+        }
         MessageContent::MessageChatChangeTitle(_) => None,
         MessageContent::MessageChatDeletePhoto(_) => None,
         MessageContent::MessageChatJoinByLink(_) => None,
         MessageContent::MessageChatUpgradeFrom(_) => None,
         MessageContent::MessageChatUpgradeTo(_) => None,
-        MessageContent::MessageContact(_) => None,
+        MessageContent::MessageContact(message_contact) => {
+            Some((Some(render_contact(message_contact.contact())), Vec::new()))
+        }
         MessageContent::MessageContactRegistered(_) => None,
         MessageContent::MessageCustomServiceAction(_) => None,
         MessageContent::MessageExpiredPhoto(_) => None,
         MessageContent::MessageExpiredVideo(_) => None,
         MessageContent::MessageInvoice(_) => None,
-        MessageContent::MessageLocation(_) => None,
+        MessageContent::MessageLocation(message_location) => Some((
+            Some(render_location(message_location.location(), None)),
+            Vec::new(),
+        )),
         MessageContent::MessagePassportDataReceived(_) => None,
         MessageContent::MessageScreenshotTaken(_) => None,
         MessageContent::MessageSticker(message_sticker) => None,
         MessageContent::MessageSupergroupChatCreate(_) => None,
 
-        MessageContent::MessageVenue(_) => None,
+        MessageContent::MessageVenue(message_venue) => {
+            let venue = message_venue.venue();
+            Some((
+                Some(render_location(venue.location(), Some(venue))),
+                Vec::new(),
+            ))
+        }
 
-        MessageContent::MessageVideoNote(message_video_note) => None,
-        MessageContent::MessageVoiceNote(_) => None,
+        MessageContent::MessageVideoNote(message_video_note) => {
+            let video_note = message_video_note.video_note();
+            // video notes are always sent as mp4 without caption
+            let file = File::from(video_note.video())
+                .with_mime_type("video/mp4")
+                .with_duration(video_note.duration());
+            Some((None, with_thumbnail(file, video_note.thumbnail())))
+        }
+        MessageContent::MessageVoiceNote(message_voice_note) => {
+            let voice_note = message_voice_note.voice_note();
+            let file = File::from(voice_note.voice())
+                .with_mime_type(voice_note.mime_type())
+                .with_duration(voice_note.duration());
+            Some((
+                Some(parse_formatted_text(message_voice_note.caption())),
+                vec![file],
+            ))
+        }
         MessageContent::MessageWebsiteConnected(_) => None,
 
         MessageContent::_Default => None,
@@ -59,80 +134,851 @@ pub fn parse_message_content(message: &MessageContent) -> Option<(Option<String>
         MessageContent::MessagePaymentSuccessfulBot(_) => None,
         MessageContent::MessagePinMessage(_) => None,
         MessageContent::MessageUnsupported(_) => None,
-        MessageContent::MessageDice(_) => None,
+        MessageContent::MessageDice(message_dice) => Some((
+            Some(format!(
+                "{} {}",
+                escape_html(message_dice.emoji()),
+                message_dice.value()
+            )),
+            Vec::new(),
+        )),
         MessageContent::MessageProximityAlertTriggered(_) => None,
     }
 }
 
+pub fn parse_forward_info(forward_info: &MessageForwardInfo) -> ForwardedFrom {
+    let mut forwarded = ForwardedFrom::default();
+    match forward_info.origin() {
+        MessageForwardOrigin::Channel(origin) => {
+            forwarded.chat_id = Some(origin.chat_id());
+            forwarded.message_id = Some(origin.message_id());
+        }
+        MessageForwardOrigin::Chat(origin) => {
+            forwarded.chat_id = Some(origin.sender_chat_id());
+        }
+        MessageForwardOrigin::HiddenUser(origin) => {
+            forwarded.name = Some(origin.sender_name().clone());
+        }
+        MessageForwardOrigin::User(origin) => {
+            forwarded.user_id = Some(origin.sender_user_id());
+        }
+        MessageForwardOrigin::_Default => {}
+    }
+    forwarded
+}
+
+/// Returns file followed by its thumbnail, if any.
+fn with_thumbnail(file: File, thumbnail: &Option<Thumbnail>) -> Vec<File> {
+    match thumbnail {
+        None => vec![file],
+        Some(thumbnail) => {
+            let thumbnail_file = File::from(thumbnail.file())
+                .with_mime_type(thumbnail_mime_type(thumbnail.format()));
+            vec![file.with_thumbnail(&thumbnail_file), thumbnail_file]
+        }
+    }
+}
+
+fn thumbnail_mime_type(format: &ThumbnailFormat) -> &'static str {
+    match format {
+        ThumbnailFormat::Png(_) => "image/png",
+        ThumbnailFormat::Webp(_) => "image/webp",
+        ThumbnailFormat::Gif(_) => "image/gif",
+        ThumbnailFormat::Mpeg4(_) => "video/mp4",
+        ThumbnailFormat::Tgs(_) => "application/x-tgsticker",
+        _ => "image/jpeg",
+    }
+}
+
+fn render_poll(poll: &Poll) -> String {
+    let options = poll
+        .options()
+        .iter()
+        .map(|option| {
+            format!(
+                "<li>{} — {}%</li>",
+                escape_html(option.text()),
+                option.vote_percentage()
+            )
+        })
+        .collect::<String>();
+    format!(
+        "<p><b>{}</b></p><ul>{}</ul><p><i>{} votes{}</i></p>",
+        escape_html(poll.question()),
+        options,
+        poll.total_voter_count(),
+        if poll.is_closed() { ", closed" } else { "" }
+    )
+}
+
+fn render_location(location: &Location, venue: Option<&Venue>) -> String {
+    let map_link = format!(
+        "https://www.openstreetmap.org/?mlat={lat}&amp;mlon={lon}#map=16/{lat}/{lon}",
+        lat = location.latitude(),
+        lon = location.longitude()
+    );
+    match venue {
+        None => format!(
+            r#"<p><a href="{}">📍 {}, {}</a></p>"#,
+            map_link,
+            location.latitude(),
+            location.longitude()
+        ),
+        Some(venue) => format!(
+            r#"<p><a href="{}">📍 <b>{}</b></a><br>{}</p>"#,
+            map_link,
+            escape_html(venue.title()),
+            escape_html(venue.address())
+        ),
+    }
+}
+
+fn render_contact(contact: &Contact) -> String {
+    let name = format!("{} {}", contact.first_name(), contact.last_name());
+    format!(
+        r#"<div class="vcard"><b>{}</b><br><a href="tel:{phone}">{phone}</a></div>"#,
+        escape_html(name.trim()),
+        phone = escape_html(contact.phone_number())
+    )
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
 pub fn parse_formatted_text(formatted_text: &FormattedText) -> String {
-    let mut entities_by_index = make_entities_stack(formatted_text.entities());
-    let mut result_text = String::new();
-    let mut current_entity = match entities_by_index.pop() {
-        None => return formatted_text.text().clone(),
-        Some(entity) => entity,
+    render_formatted_text(formatted_text, &HtmlRenderer, None)
+}
+
+/// Renders text with entities, hashtags are linked to search in the `channel` if it's known.
+pub fn render_formatted_text(
+    formatted_text: &FormattedText,
+    renderer: &dyn Renderer,
+    channel: Option<&str>,
+) -> String {
+    let nodes = parse_entities(formatted_text, channel);
+    let mut result_text = String::with_capacity(formatted_text.text().len());
+    render_nodes(&nodes, renderer, false, &mut result_text);
+    result_text
+}
+
+pub fn renderer(format: TextFormat) -> &'static dyn Renderer {
+    match format {
+        TextFormat::Html => &HtmlRenderer,
+        TextFormat::Markdown => &MarkdownRenderer,
+        TextFormat::Plain => &PlainRenderer,
+    }
+}
+
+/// Link preview of text message, its photo is one of the message files.
+pub fn message_web_page(message: &MessageContent, photos: &PhotoSettings) -> Option<WebPage> {
+    let web_page = match message {
+        MessageContent::MessageText(text) => text.web_page().as_ref()?,
+        _ => return None,
     };
-    for (i, ch) in formatted_text.text().chars().enumerate() {
-        if i == current_entity.0 {
-            result_text = format!("{}{}{}", result_text, current_entity.1, ch);
-            current_entity = match entities_by_index.pop() {
-                None => {
-                    result_text = format!(
-                        "{}{}",
-                        result_text,
-                        &formatted_text
-                            .text()
-                            .chars()
-                            .skip(i + 1)
-                            .take(formatted_text.text().len() - i)
-                            .collect::<String>()
-                    );
-                    return result_text;
-                }
-                Some(entity) => entity,
-            };
-        } else {
-            result_text.push(ch)
+    Some(WebPage {
+        url: web_page.url().clone(),
+        display_url: web_page.display_url().clone(),
+        site_name: non_empty(web_page.site_name()),
+        title: non_empty(web_page.title()),
+        description: non_empty(web_page.description().text()),
+        photo: web_page
+            .photo()
+            .as_ref()
+            .and_then(|photo| photo_files(photo, photos).into_iter().next())
+            .map(|f| f.remote_id),
+    })
+}
+
+/// Files of the photo sizes chosen by the policy, the main one goes first.
+fn photo_files(photo: &Photo, photos: &PhotoSettings) -> Vec<File> {
+    let sizes = photo.sizes();
+    let area = |size: &&PhotoSize| size.width() as i64 * size.height() as i64;
+    let largest = sizes.iter().max_by_key(area);
+    let smallest = sizes.iter().min_by_key(area);
+    match photos.size_policy {
+        PhotoSizePolicy::Largest => largest.map(photo_size_file).into_iter().collect(),
+        PhotoSizePolicy::Limited => sizes
+            .iter()
+            .filter(|size| fits(size, photos))
+            .max_by_key(area)
+            // the smallest size is better than nothing
+            .or(smallest)
+            .map(photo_size_file)
+            .into_iter()
+            .collect(),
+        PhotoSizePolicy::Both => match (largest, smallest) {
+            (Some(largest), Some(smallest)) if largest.photo().id() != smallest.photo().id() => {
+                let thumbnail = photo_size_file(smallest);
+                vec![
+                    photo_size_file(largest).with_thumbnail(&thumbnail),
+                    thumbnail,
+                ]
+            }
+            _ => largest.map(photo_size_file).into_iter().collect(),
+        },
+    }
+}
+
+fn fits(size: &PhotoSize, photos: &PhotoSettings) -> bool {
+    let bytes = size.photo().size().max(size.photo().expected_size()) as i64;
+    let side_fits = photos
+        .max_side
+        .is_none_or(|max| size.width() <= max && size.height() <= max);
+    let bytes_fits = photos.max_bytes.is_none_or(|max| bytes <= max);
+    side_fits && bytes_fits
+}
+
+fn photo_size_file(size: &PhotoSize) -> File {
+    File::from(size.photo())
+        .with_mime_type("image/jpeg")
+        .with_dimensions(size.width(), size.height())
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    Some(text.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+/// Text of the first bold entity, if there is a non-empty one.
+pub fn first_bold(formatted_text: &FormattedText) -> Option<String> {
+    let text: Vec<u16> = formatted_text.text().encode_utf16().collect();
+    formatted_text
+        .entities()
+        .iter()
+        .filter(|entity| matches!(entity.type_(), TextEntityType::Bold(_)))
+        .map(|entity| {
+            let start = (entity.offset() as usize).min(text.len());
+            let end = (start + entity.length() as usize).min(text.len());
+            String::from_utf16_lossy(&text[start..end])
+                .trim()
+                .to_string()
+        })
+        .find(|bold| !bold.is_empty())
+}
+
+/// Text with entities the post content was made of, if any.
+pub fn message_formatted_text(message: &MessageContent) -> Option<&FormattedText> {
+    match message {
+        MessageContent::MessageText(text) => Some(text.text()),
+        MessageContent::MessageAnimation(message_animation) => Some(message_animation.caption()),
+        MessageContent::MessageAudio(message_audio) => Some(message_audio.caption()),
+        MessageContent::MessageDocument(message_document) => Some(message_document.caption()),
+        MessageContent::MessagePhoto(photo) => Some(photo.caption()),
+        MessageContent::MessageVideo(message_video) => Some(message_video.caption()),
+        MessageContent::MessageVoiceNote(message_voice_note) => Some(message_voice_note.caption()),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Entity {
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+    Code,
+    Pre,
+    PreCode(String),
+    /// Link with custom text.
+    TextUrl(String),
+    /// Link which text is the link itself: url, mention, hashtag, email, etc.
+    Link(String),
+}
+
+impl Entity {
+    /// Content of such entities must be kept as is.
+    fn is_verbatim(&self) -> bool {
+        matches!(self, Entity::Code | Entity::Pre | Entity::PreCode(_))
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Entity(Entity, Vec<Node>),
+}
+
+/// Output format of text with entities, shared between request handlers.
+pub trait Renderer: Sync {
+    /// Escapes regular text.
+    fn text(&self, text: &str) -> String;
+
+    /// Escapes content of code blocks and urls.
+    fn verbatim(&self, text: &str) -> String;
+
+    fn open(&self, entity: &Entity) -> String;
+
+    fn close(&self, entity: &Entity) -> String;
+
+    fn paragraph(&self, rendered: &str) -> String {
+        format!("{}\n\n", rendered)
+    }
+
+    fn quote(&self, rendered: &str) -> String {
+        self.paragraph(rendered)
+    }
+
+    /// Image by url, formats without images render nothing.
+    fn image(&self, _url: &str, _alt: &str) -> String {
+        "".to_string()
+    }
+
+    /// Wraps already rendered content into the entity.
+    fn wrap(&self, entity: &Entity, rendered: &str) -> String {
+        format!("{}{}{}", self.open(entity), rendered, self.close(entity))
+    }
+}
+
+pub struct HtmlRenderer;
+
+impl Renderer for HtmlRenderer {
+    fn text(&self, text: &str) -> String {
+        escape_html(text)
+    }
+
+    fn verbatim(&self, text: &str) -> String {
+        escape_html(text)
+    }
+
+    fn paragraph(&self, rendered: &str) -> String {
+        format!("<p>{}</p>", rendered)
+    }
+
+    fn quote(&self, rendered: &str) -> String {
+        format!("<blockquote>{}</blockquote>", rendered)
+    }
+
+    fn image(&self, url: &str, alt: &str) -> String {
+        format!(
+            r#"<img src="{}" alt="{}">"#,
+            escape_html(url),
+            escape_html(alt)
+        )
+    }
+
+    fn open(&self, entity: &Entity) -> String {
+        match entity {
+            Entity::Bold => "<b>".to_string(),
+            Entity::Italic => "<i>".to_string(),
+            Entity::Underline => "<u>".to_string(),
+            Entity::Strikethrough => "<s>".to_string(),
+            Entity::Code => "<code>".to_string(),
+            Entity::Pre => "<pre>".to_string(),
+            Entity::PreCode(language) if language.is_empty() => "<pre><code>".to_string(),
+            Entity::PreCode(language) => {
+                format!(r#"<pre><code class="language-{}">"#, escape_html(language))
+            }
+            Entity::TextUrl(url) | Entity::Link(url) => {
+                format!(r#"<a href="{}">"#, escape_html(url))
+            }
         }
     }
-    result_text
+
+    fn close(&self, entity: &Entity) -> String {
+        match entity {
+            Entity::Bold => "</b>",
+            Entity::Italic => "</i>",
+            Entity::Underline => "</u>",
+            Entity::Strikethrough => "</s>",
+            Entity::Code => "</code>",
+            Entity::Pre => "</pre>",
+            Entity::PreCode(_) => "</code></pre>",
+            Entity::TextUrl(_) | Entity::Link(_) => "</a>",
+        }
+        .to_string()
+    }
+}
+
+pub struct MarkdownRenderer;
+
+const MARKDOWN_SPECIAL_CHARS: &str = "\\`*_[]<>~|";
+
+impl Renderer for MarkdownRenderer {
+    fn text(&self, text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for ch in text.chars() {
+            if MARKDOWN_SPECIAL_CHARS.contains(ch) {
+                escaped.push('\\');
+            }
+            escaped.push(ch);
+        }
+        escaped
+    }
+
+    fn verbatim(&self, text: &str) -> String {
+        text.to_string()
+    }
+
+    fn open(&self, entity: &Entity) -> String {
+        match entity {
+            Entity::Bold => "**".to_string(),
+            Entity::Italic => "_".to_string(),
+            Entity::Strikethrough => "~~".to_string(),
+            Entity::Code => "`".to_string(),
+            Entity::Pre => "\n```\n".to_string(),
+            Entity::PreCode(language) => format!("\n```{}\n", language),
+            Entity::TextUrl(_) | Entity::Link(_) => "[".to_string(),
+            Entity::Underline => "".to_string(),
+        }
+    }
+
+    fn close(&self, entity: &Entity) -> String {
+        match entity {
+            Entity::Bold => "**".to_string(),
+            Entity::Italic => "_".to_string(),
+            Entity::Strikethrough => "~~".to_string(),
+            Entity::Code => "`".to_string(),
+            Entity::Pre | Entity::PreCode(_) => "\n```\n".to_string(),
+            Entity::TextUrl(url) | Entity::Link(url) => format!("]({})", url),
+            Entity::Underline => "".to_string(),
+        }
+    }
+
+    fn quote(&self, rendered: &str) -> String {
+        let quoted: Vec<String> = rendered
+            .trim_end()
+            .lines()
+            .map(|line| format!("> {}", line))
+            .collect();
+        self.paragraph(quoted.join("\n").as_str())
+    }
+
+    fn image(&self, url: &str, alt: &str) -> String {
+        format!("![{}]({})", self.text(alt), url)
+    }
+}
+
+pub struct PlainRenderer;
+
+impl Renderer for PlainRenderer {
+    fn text(&self, text: &str) -> String {
+        text.to_string()
+    }
+
+    fn verbatim(&self, text: &str) -> String {
+        text.to_string()
+    }
+
+    fn open(&self, _entity: &Entity) -> String {
+        "".to_string()
+    }
+
+    fn close(&self, entity: &Entity) -> String {
+        match entity {
+            Entity::TextUrl(url) => format!(" ({})", url),
+            _ => "".to_string(),
+        }
+    }
+}
+
+/// Entity bounds in UTF-16 code units, as telegram measures them.
+#[derive(Debug, Clone)]
+struct Span {
+    start: usize,
+    end: usize,
+    kind: Entity,
 }
 
-fn make_entities_stack(entities: &[TextEntity]) -> Vec<(usize, String)> {
-    let mut stack = Vec::new();
-    for entity in entities {
-        let formatting = match entity.type_() {
-            TextEntityType::Bold(_) => Some(("<b>".to_string(), "</b>".to_string())),
-            TextEntityType::Code(_) => Some(("<code>".to_string(), "</code>".to_string())),
-            TextEntityType::Hashtag(_) => Some(("#".to_string(), "".to_string())),
-            TextEntityType::Italic(_) => Some(("<i>".to_string(), "</i>".to_string())),
-            TextEntityType::PhoneNumber(_) => Some(("<phone>".to_string(), "</phone>".to_string())),
-            TextEntityType::Pre(_) => Some(("<pre>".to_string(), "</pre>".to_string())),
-            TextEntityType::PreCode(_) => {
-                Some(("<pre><code>".to_string(), "</code></pre>".to_string()))
+/// Builds tree of properly nested entities.
+fn parse_entities(formatted_text: &FormattedText, channel: Option<&str>) -> Vec<Node> {
+    let text: Vec<u16> = formatted_text.text().encode_utf16().collect();
+    let spans = formatted_text
+        .entities()
+        .iter()
+        .filter_map(|entity| {
+            let start = (entity.offset() as usize).min(text.len());
+            let end = (start + entity.length() as usize).min(text.len());
+            let covered = String::from_utf16_lossy(&text[start..end]);
+            entity_kind(entity.type_(), covered.as_str(), channel).map(|kind| Span {
+                start,
+                end,
+                kind,
+            })
+        })
+        .collect();
+    build_nodes(&text, 0, text.len(), spans)
+}
+
+/// Entity crossing the end of enclosing one is split in two,
+/// so that the rest of it continues after the enclosing entity is closed.
+fn build_nodes(text: &[u16], start: usize, end: usize, mut spans: Vec<Span>) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    let mut pos = start;
+    loop {
+        // outer entities go before inner ones starting at the same offset
+        spans.sort_by_key(|span| (span.start, usize::MAX - span.end));
+        if spans.is_empty() {
+            break;
+        }
+        let span = spans.remove(0);
+        let (span_start, span_end) = (span.start.max(pos), span.end.min(end));
+        if span_start >= span_end {
+            continue;
+        }
+        if span_start > pos {
+            nodes.push(Node::Text(String::from_utf16_lossy(&text[pos..span_start])));
+        }
+        let mut children = Vec::new();
+        let mut rest = Vec::new();
+        for other in spans.drain(..) {
+            if other.start >= span_end {
+                rest.push(other);
+                continue;
             }
-            TextEntityType::Strikethrough(_) => {
-                Some(("<strike>".to_string(), "</strike>".to_string()))
+            if other.end > span_end {
+                rest.push(Span {
+                    start: span_end,
+                    ..other.clone()
+                });
             }
-            TextEntityType::TextUrl(u) => {
-                let tag = format!(r#"<a href="{}">"#, u.url());
-                Some((tag, "</a>".to_string()))
+            children.push(Span {
+                end: other.end.min(span_end),
+                ..other
+            });
+        }
+        spans = rest;
+        nodes.push(Node::Entity(
+            span.kind,
+            build_nodes(text, span_start, span_end, children),
+        ));
+        pos = span_end;
+    }
+    if pos < end {
+        nodes.push(Node::Text(String::from_utf16_lossy(&text[pos..end])));
+    }
+    nodes
+}
+
+/// Maps telegram entity to the rendered one, `covered` is the text of the entity.
+fn entity_kind(
+    entity_type: &TextEntityType,
+    covered: &str,
+    channel: Option<&str>,
+) -> Option<Entity> {
+    match entity_type {
+        TextEntityType::Bold(_) => Some(Entity::Bold),
+        TextEntityType::Code(_) => Some(Entity::Code),
+        TextEntityType::Italic(_) => Some(Entity::Italic),
+        TextEntityType::PhoneNumber(_) => {
+            Some(Entity::Link(format!("tel:{}", phone_digits(covered))))
+        }
+        TextEntityType::Pre(_) => Some(Entity::Pre),
+        TextEntityType::PreCode(pre_code) => Some(Entity::PreCode(pre_code.language().clone())),
+        TextEntityType::Strikethrough(_) => Some(Entity::Strikethrough),
+        TextEntityType::TextUrl(u) => Some(Entity::TextUrl(u.url().clone())),
+        TextEntityType::Underline(_) => Some(Entity::Underline),
+        TextEntityType::Url(_) => Some(Entity::Link(url_href(covered))),
+        TextEntityType::Mention(_) => Some(Entity::Link(format!(
+            "https://t.me/{}",
+            covered.trim_start_matches('@')
+        ))),
+        TextEntityType::MentionName(mention) => {
+            Some(Entity::Link(format!("tg://user?id={}", mention.user_id())))
+        }
+        TextEntityType::EmailAddress(_) => Some(Entity::Link(format!("mailto:{}", covered))),
+        TextEntityType::Hashtag(_) | TextEntityType::Cashtag(_) => {
+            channel.map(|channel| Entity::Link(search_url(channel, covered)))
+        }
+        TextEntityType::_Default => None,
+        TextEntityType::BotCommand(_) => None,
+        TextEntityType::BankCardNumber(_) => None,
+    }
+}
+
+fn render_nodes(nodes: &[Node], renderer: &dyn Renderer, verbatim: bool, out: &mut String) {
+    for node in nodes.iter() {
+        match node {
+            Node::Text(text) if verbatim => out.push_str(renderer.verbatim(text).as_str()),
+            Node::Text(text) => out.push_str(renderer.text(text).as_str()),
+            Node::Entity(entity, children) => {
+                out.push_str(renderer.open(entity).as_str());
+                render_nodes(children, renderer, verbatim || entity.is_verbatim(), out);
+                out.push_str(renderer.close(entity).as_str());
             }
-            TextEntityType::Underline(_) => Some(("<u>".to_string(), "</u>".to_string())),
-            TextEntityType::Url(_) => Some(("<a>".to_string(), "</a>".to_string())),
-            TextEntityType::_Default => None,
-            TextEntityType::BotCommand(_) => None,
-            TextEntityType::Cashtag(_) => None,
-            TextEntityType::EmailAddress(_) => None,
-            TextEntityType::Mention(_) => None,
-            TextEntityType::MentionName(_) => None,
-            TextEntityType::BankCardNumber(_) => None,
-        };
-        if let Some((start_tag, end_tag)) = formatting {
-            stack.push((entity.offset() as usize, start_tag));
-            stack.push(((entity.offset() + entity.length()) as usize, end_tag));
-        }
-    }
-    stack.sort_by_key(|(i, _)| *i);
-    stack.reverse();
-    stack
+        }
+    }
+}
+
+fn url_href(url: &str) -> String {
+    match url.contains("://") {
+        true => url.to_string(),
+        false => format!("http://{}", url),
+    }
+}
+
+/// Link to search in channel's web preview.
+fn search_url(channel: &str, query: &str) -> String {
+    let mut encoded = String::with_capacity(query.len());
+    for byte in query.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(format!("%{:02X}", byte).as_str()),
+        }
+    }
+    format!("https://t.me/s/{}?q={}", channel, encoded)
+}
+
+fn phone_digits(phone: &str) -> String {
+    phone
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == '+')
+        .collect()
+}
+
+/// Converts html content to plain text, for posts which have no formatted text source.
+/// Line breaks and block ends become newlines, so polls, venues and contacts keep their lines.
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut tag: Option<String> = None;
+    for ch in html.chars() {
+        match (ch, tag.as_mut()) {
+            ('<', None) => tag = Some(String::new()),
+            ('>', Some(name)) => {
+                let name = name.split_whitespace().next().unwrap_or_default();
+                let name = name.trim_end_matches('/').to_ascii_lowercase();
+                let block = matches!(
+                    name.as_str(),
+                    "br" | "p" | "/p" | "div" | "/div" | "ul" | "/ul" | "/li"
+                );
+                if block && !text.is_empty() && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                tag = None;
+            }
+            (_, Some(name)) => name.push(ch),
+            (_, None) => text.push(ch),
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Formatted text of a channel post, as tdlib sends it.
+    fn fixture(name: &str) -> FormattedText {
+        let path = format!(
+            "{}/tests/fixtures/formatted_text/{}.json",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        );
+        FormattedText::from_json(std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn formatted(text: &str, entities: Vec<serde_json::Value>) -> FormattedText {
+        let json = serde_json::json!({
+            "@type": "formattedText",
+            "text": text,
+            "entities": entities,
+        });
+        FormattedText::from_json(json.to_string()).unwrap()
+    }
+
+    fn entity(offset: i32, length: i32, type_: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "@type": "textEntity",
+            "offset": offset,
+            "length": length,
+            "type": type_,
+        })
+    }
+
+    fn entity_type(name: &str) -> serde_json::Value {
+        serde_json::json!({ "@type": name })
+    }
+
+    #[test]
+    fn flag_before_entity() {
+        assert_eq!(
+            parse_formatted_text(&fixture("flag")),
+            "🇺🇦 <b>Kyiv, 12 March</b>\n\n\
+            Air defence is active in the region. Stay in shelters until the all clear.\n\n\
+            More details on the <a href=\"https://example.gov.ua/alerts\">official site</a>."
+        );
+    }
+
+    #[test]
+    fn zwj_sequence_before_entity() {
+        assert_eq!(
+            parse_formatted_text(&fixture("zwj_sequence")),
+            "👨\u{200d}👩\u{200d}👧\u{200d}👦 <b>Family weekend</b> at the park!\n\
+            <i>Free entry</i> for kids under 7."
+        );
+    }
+
+    #[test]
+    fn entities_bounded_by_surrogate_pairs() {
+        assert_eq!(
+            parse_formatted_text(&fixture("surrogate_bounds")),
+            "<b>🔥🔥🔥 SALE 🔥🔥🔥</b>\n🚀<u>Launch</u> day: new app version\nCongrats <i>🎉</i>"
+        );
+    }
+
+    #[test]
+    fn skin_tone_modifier_before_entity() {
+        assert_eq!(
+            parse_formatted_text(&fixture("skin_tone")),
+            "👍🏽 Thanks for <b>10k subscribers</b>! \
+            See you in the <a href=\"https://youtube.com/live\">next stream 🎮</a>"
+        );
+    }
+
+    #[test]
+    fn entities_starting_and_ending_at_same_offset() {
+        assert_eq!(
+            parse_formatted_text(&fixture("same_offsets")),
+            "📌 <b><i>Pinned</i>: <u>schedule for the week</u></b>\n\
+            <b>Monday <i>🏋\u{fe0f} gym</i></b>, Tuesday 🏊 pool"
+        );
+    }
+
+    #[test]
+    fn code_after_emoji() {
+        assert_eq!(
+            parse_formatted_text(&fixture("code")),
+            "🐍 <b>Python tip of the day</b>:\n\n<pre>sorted(items, key=len)</pre>\n\n\
+            Sorts items by <code>length</code>, shortest first 👌"
+        );
+    }
+
+    #[test]
+    fn text_is_escaped_inside_and_outside_entities() {
+        let url = serde_json::json!({
+            "@type": "textEntityTypeTextUrl",
+            "url": "https://example.com/?a=1&b=\"2\"",
+        });
+        let text = formatted(
+            r#"a < b & "c" and a < b & "c""#,
+            vec![
+                entity(12, 3, url),
+                entity(16, 11, entity_type("textEntityTypeBold")),
+            ],
+        );
+        assert_eq!(
+            parse_formatted_text(&text),
+            "a &lt; b &amp; &quot;c&quot; \
+            <a href=\"https://example.com/?a=1&amp;b=&quot;2&quot;\">and</a> \
+            <b>a &lt; b &amp; &quot;c&quot;</b>"
+        );
+    }
+
+    #[test]
+    fn overlapping_entities_are_split() {
+        let text = formatted(
+            "bold both italic",
+            vec![
+                entity(0, 9, entity_type("textEntityTypeBold")),
+                entity(5, 11, entity_type("textEntityTypeItalic")),
+            ],
+        );
+        assert_eq!(
+            parse_formatted_text(&text),
+            "<b>bold <i>both</i></b><i> italic</i>"
+        );
+    }
+
+    #[test]
+    fn phone_number_links_to_tel() {
+        let text = formatted(
+            "Call +1 (555) 010-99 now",
+            vec![entity(5, 15, entity_type("textEntityTypePhoneNumber"))],
+        );
+        let html = parse_formatted_text(&text);
+        assert_eq!(
+            html,
+            "Call <a href=\"tel:+155501099\">+1 (555) 010-99</a> now"
+        );
+        assert!(!html.contains("<phone>"));
+    }
+
+    #[test]
+    fn bare_url_gets_http_scheme() {
+        let text = formatted(
+            "Docs: example.com/a?b=1&c=2 and https://t.me/x",
+            vec![
+                entity(6, 21, entity_type("textEntityTypeUrl")),
+                entity(32, 14, entity_type("textEntityTypeUrl")),
+            ],
+        );
+        let html = parse_formatted_text(&text);
+        assert_eq!(
+            html,
+            "Docs: <a href=\"http://example.com/a?b=1&amp;c=2\">example.com/a?b=1&amp;c=2</a> \
+            and <a href=\"https://t.me/x\">https://t.me/x</a>"
+        );
+        assert!(!html.contains("<a>"));
+    }
+
+    #[test]
+    fn mentions_link_to_users() {
+        let mention_name = serde_json::json!({
+            "@type": "textEntityTypeMentionName",
+            "user_id": 42,
+        });
+        let text = formatted(
+            "Thanks @durov and Pavel",
+            vec![
+                entity(7, 6, entity_type("textEntityTypeMention")),
+                entity(18, 5, mention_name),
+            ],
+        );
+        assert_eq!(
+            parse_formatted_text(&text),
+            "Thanks <a href=\"https://t.me/durov\">@durov</a> \
+            and <a href=\"tg://user?id=42\">Pavel</a>"
+        );
+    }
+
+    #[test]
+    fn email_links_to_mailto() {
+        let text = formatted(
+            "Write to news@example.com",
+            vec![entity(9, 16, entity_type("textEntityTypeEmailAddress"))],
+        );
+        assert_eq!(
+            parse_formatted_text(&text),
+            "Write to <a href=\"mailto:news@example.com\">news@example.com</a>"
+        );
+    }
+
+    #[test]
+    fn hashtags_and_cashtags_link_to_channel_search() {
+        let text = formatted(
+            "#новости and $USD",
+            vec![
+                entity(0, 8, entity_type("textEntityTypeHashtag")),
+                entity(13, 4, entity_type("textEntityTypeCashtag")),
+            ],
+        );
+        assert_eq!(
+            render_formatted_text(&text, &HtmlRenderer, Some("channel")),
+            "<a href=\"https://t.me/s/channel?q=%23%D0%BD%D0%BE%D0%B2%D0%BE%D1%81%D1%82%D0%B8\">\
+            #новости</a> and <a href=\"https://t.me/s/channel?q=%24USD\">$USD</a>"
+        );
+        assert_eq!(
+            render_formatted_text(&text, &MarkdownRenderer, Some("channel")),
+            "[#новости](https://t.me/s/channel?q=%23%D0%BD%D0%BE%D0%B2%D0%BE%D1%81%D1%82%D0%B8) \
+            and [$USD](https://t.me/s/channel?q=%24USD)"
+        );
+        // without a channel there is nothing to search in
+        assert_eq!(parse_formatted_text(&text), "#новости and $USD");
+    }
 }